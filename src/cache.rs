@@ -1,52 +1,72 @@
+use actix_web::web::Bytes;
 use log::{debug, error, info};
 use notify::{
     EventKind, RecommendedWatcher, RecursiveMode, Watcher,
-    event::{ModifyKind, RenameMode},
+    event::{AccessKind, AccessMode, ModifyKind, RenameMode},
 };
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
+use std::{fmt::Display, path::PathBuf, sync::Arc};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 pub trait CacheTrait {
     type Error;
     type DataSource;
     type Data;
     type Key;
+    type Variant;
 
-    async fn insert_data(&mut self, data: &Self::DataSource) -> Result<Self::Key, Self::Error>;
-    async fn remove_data(&mut self, data: &Self::DataSource) -> Option<Self::Data>;
-    async fn get_data(&mut self, key: &Self::Key) -> Result<Self::Data, Self::Error>;
+    async fn insert_data(&self, data: &Self::DataSource) -> Result<Self::Key, Self::Error>;
+    async fn remove_data(&self, data: &Self::DataSource) -> Option<Self::Data>;
+    async fn get_data(&self, key: &Self::Key) -> Result<Self::Data, Self::Error>;
     async fn get_data_bytes(
-        &mut self,
+        &self,
         key: &Self::Key,
-        compress: bool,
-    ) -> Result<(String, Vec<u8>), Self::Error>;
+        variant: &Self::Variant,
+    ) -> Result<(String, Bytes), Self::Error>;
     fn len(&self) -> usize;
     fn directories(&self) -> Vec<PathBuf>;
-    fn clean_cache(&mut self);
+    fn clean_cache(&self);
+    fn persist(&self);
+    fn queue_warmup(&self, key: Self::Key);
+    fn warmup_queue(&self) -> Option<UnboundedReceiver<Self::Key>>;
+    async fn warm_data(&self, key: &Self::Key) -> Result<(), Self::Error>;
 }
 
 // Background processes
-pub async fn cache_cleanup<C: CacheTrait>(cache: Arc<Mutex<C>>) {
+pub async fn cache_cleanup<C: CacheTrait>(cache: Arc<C>) {
     use tokio::time::{Duration, sleep};
     debug!("Beginning cache clean up thread");
     loop {
         sleep(Duration::from_secs(60)).await;
-        {
-            let mut cache_lock = cache.lock().await;
-            cache_lock.clean_cache();
+        cache.clean_cache();
+    }
+}
+
+pub async fn prewarm_worker<C>(cache: Arc<C>)
+where
+    C: CacheTrait,
+    C::Error: Display,
+{
+    debug!("Starting pre-warm thread");
+    let Some(mut queue) = cache.warmup_queue() else {
+        error!("Pre-warm queue is already being processed");
+        return;
+    };
+
+    while let Some(key) = queue.recv().await {
+        if let Err(e) = cache.warm_data(&key).await {
+            error!("Error pre-warming image: {}", e);
         }
     }
+
+    debug!("Pre-warm thread stopped.");
 }
 
-pub async fn directory_watcher<C>(cache: Arc<Mutex<C>>)
+pub async fn directory_watcher<C>(cache: Arc<C>)
 where
     C: CacheTrait<DataSource = PathBuf>,
 {
     debug!("Starting directory watcher thread");
-    let directories = {
-        let cache_lock = cache.lock().await;
-        cache_lock.directories()
-    };
+    let directories = cache.directories();
 
     let (tx, mut rx) = tokio::sync::mpsc::channel(100);
     let mut watcher = match RecommendedWatcher::new(
@@ -74,18 +94,22 @@ where
     while let Some(res) = rx.recv().await {
         match res {
             Ok(event) => match event.kind {
-                EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
-                    let mut cache_lock = cache.lock().await;
+                // Files written in place, like edited caption sidecars, are picked up once closed
+                EventKind::Create(_)
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
                     for path in event.paths {
-                        #[allow(unused)]
-                        cache_lock.insert_data(&path).await;
+                        if let Ok(key) = cache.insert_data(&path).await {
+                            cache.queue_warmup(key);
+                        }
                     }
+                    cache.persist();
                 }
                 EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
-                    let mut cache_lock = cache.lock().await;
                     for path in event.paths {
-                        cache_lock.remove_data(&path).await;
+                        cache.remove_data(&path).await;
                     }
+                    cache.persist();
                 }
                 _ => {}
             },
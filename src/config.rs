@@ -1,18 +1,145 @@
+use crate::image_cache::daily::DailyPolicy;
+use crate::image_cache::image::{DateSource, MetaField};
 use anyhow::anyhow;
+use chrono_tz::Tz;
 use confique::Config;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
 
 pub static CONFIG_PATH: &'static str = "/etc/jorge-a-day/config.toml";
 
+/// Paths that collections cannot be named after since the top level routes use them
+const RESERVED_COLLECTIONS: [&str; 9] = [
+    "about",
+    "admin",
+    "album",
+    "albums",
+    "daily",
+    "daily.json",
+    "favicon.ico",
+    "images",
+    "stats",
+];
+
 #[derive(Config, Deserialize, Clone)]
 pub struct AppConfig {
-    #[config(default = ["."])]
-    pub directories: Vec<String>,
+    /// Image directories of the top level, the working directory unless collections are set
+    #[config()]
+    pub directories: Option<Vec<String>>,
+
+    /// Shown on top of the gallery
+    #[config(default = "Jorge a day!")]
+    pub title: String,
+
+    /// Separate feeds served under `/{name}/`, each with its own images and daily image
+    #[config(default = {})]
+    pub collections: BTreeMap<String, CollectionConfig>,
+
+    /// Seconds a derivative may go unused before it is dropped from memory
+    #[config(default = 3600)]
+    pub memory_idle_secs: u32,
+
+    /// Memory budget for encoded derivatives in megabytes
+    #[config(default = 256)]
+    pub memory_budget_mb: usize,
+
+    #[config(default = "/etc/jorge-a-day/index.json")]
+    pub index_path: String,
+
+    /// Where uploaded images are written, defaults to the first of `directories`
+    #[config()]
+    pub upload_dir: Option<String>,
 
+    /// Largest accepted upload in megabytes
+    #[config(default = 50)]
+    pub max_upload_mb: usize,
+
+    /// Bearer tokens allowed to use the authenticated endpoints
+    #[config(default = [])]
+    pub api_tokens: Vec<String>,
+
+    /// Images that are hidden from every listing, kept as a JSON list of IDs
+    #[config(default = "/etc/jorge-a-day/hidden.json")]
+    pub hidden_path: String,
+
+    /// Captions set through the admin API
+    #[config(default = "/etc/jorge-a-day/captions.json")]
+    pub captions_path: String,
+
+    /// Named albums and the tags of the images in them, e.g. `birthday = ["birthday", "party"]`
+    #[config(default = {})]
+    pub albums: BTreeMap<String, Vec<String>>,
+
+    /// Where deleted images are moved to
+    #[config(default = "/etc/jorge-a-day/trash")]
+    pub trash_dir: String,
+
+    #[config(default = "/var/cache/jorge-a-day")]
+    pub derivative_dir: String,
+
+    /// Where image dates are read from, the first one an image has wins
+    #[config(default = ["date-time-original", "create-date", "modified"])]
+    pub date_sources: Vec<DateSource>,
+
+    /// Remove GPS and other identifying EXIF/XMP metadata from originals before serving them
+    #[config(default = false)]
+    pub strip_metadata: bool,
+
+    /// EXIF details exposed by the images API, GPS is never exposed
+    #[config(default = ["width", "height", "camera", "lens", "exposure", "iso", "focal-length"])]
+    pub exif_fields: Vec<MetaField>,
+
+    /// Widths and heights clients are allowed to request resized images at
+    #[config(default = [200, 400, 800, 1600])]
+    pub allowed_sizes: Vec<u32>,
+
+    /// How many images may be decoded and encoded at once
+    #[config(default = 2)]
+    pub encode_concurrency: usize,
+
+    /// How many encodes may wait for a free encoder before requests get a 503
+    #[config(default = 16)]
+    pub encode_queue_depth: usize,
+
+    /// How many of the newest images get their derivatives encoded at startup
+    #[config(default = 50)]
+    pub prewarm_count: usize,
+
+    /// Widths of the thumbnails encoded ahead of time
+    #[config(default = [400])]
+    pub prewarm_sizes: Vec<u32>,
+
+    /// Cache-Control max-age in seconds for original images
+    #[config(default = 86400)]
+    pub original_max_age: u32,
+
+    /// Cache-Control max-age in seconds for compressed and resized images
+    #[config(default = 604800)]
+    pub derivative_max_age: u32,
+
+    /// IANA timezone whose midnight starts a new daily image, also used for EXIF dates without an offset
+    #[config(default = "UTC")]
+    pub timezone: Tz,
+
+    /// How /daily picks its image: newest, random, round-robin or schedule
+    #[config(default = "newest")]
+    pub daily_policy: DailyPolicy,
+
+    /// JSON file mapping dates (YYYY-MM-DD) to image IDs, used by the schedule policy
+    #[config(default = "/etc/jorge-a-day/schedule.json")]
+    pub daily_schedule_path: String,
+
+    /// Where past daily images and round-robin progress are remembered
+    #[config(default = "/etc/jorge-a-day/daily.json")]
+    pub daily_state_path: String,
+
+    /// Cache-Control max-age in seconds for /daily
     #[config(default = 300)]
-    pub cache_age: i64,
+    pub daily_max_age: u32,
 
     #[config(default = "0.0.0.0:8443")]
     pub address: String,
@@ -27,7 +154,96 @@ pub struct AppConfig {
     pub key: Option<String>,
 }
 
+/// Settings of a collection, anything not set here is shared with the top level config
+#[derive(Deserialize, Clone)]
+pub struct CollectionConfig {
+    pub directories: Vec<String>,
+    pub title: Option<String>,
+    pub daily_policy: Option<DailyPolicy>,
+    pub upload_dir: Option<String>,
+}
+
 impl AppConfig {
+    /// The config a collection is served with. State files and derivatives get their own
+    /// paths so collections do not overwrite or prune each other's.
+    pub fn for_collection(&self, name: &str) -> anyhow::Result<AppConfig> {
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or_else(|| anyhow!("No collection named {}", name))?;
+        check_collection_name(name)?;
+        // Image directories are canonicalized at startup, a typo would otherwise panic there
+        if let Some(missing) = collection
+            .directories
+            .iter()
+            .find(|directory| !Path::new(directory).is_dir())
+        {
+            anyhow::bail!(
+                "Directory {} of collection {} does not exist",
+                missing,
+                name
+            );
+        }
+
+        let file = |path: &str| collection_file(path, name);
+        let dir = |path: &str| Path::new(path).join(name).to_string_lossy().into_owned();
+
+        Ok(AppConfig {
+            directories: Some(collection.directories.clone()),
+            title: collection.title.clone().unwrap_or_else(|| name.to_owned()),
+            collections: BTreeMap::new(),
+            daily_policy: collection.daily_policy.unwrap_or(self.daily_policy),
+            upload_dir: collection.upload_dir.clone(),
+            index_path: file(&self.index_path),
+            hidden_path: file(&self.hidden_path),
+            captions_path: file(&self.captions_path),
+            daily_schedule_path: file(&self.daily_schedule_path),
+            daily_state_path: file(&self.daily_state_path),
+            derivative_dir: dir(&self.derivative_dir),
+            trash_dir: dir(&self.trash_dir),
+            ..self.clone()
+        })
+    }
+
+    /// Image directories of the top level. A server that only hosts collections can leave
+    /// them out, the top level is then empty.
+    pub fn image_directories(&self) -> Vec<String> {
+        match &self.directories {
+            Some(directories) => directories.clone(),
+            None if self.collections.is_empty() => vec![".".to_owned()],
+            None => Vec::new(),
+        }
+    }
+
+    /// Image and upload directories of the top level and every collection, each listed once
+    pub fn all_directories(&self) -> Vec<String> {
+        self.collections
+            .values()
+            .flat_map(|collection| {
+                collection
+                    .directories
+                    .iter()
+                    .chain(&collection.upload_dir)
+                    .cloned()
+            })
+            .chain(self.image_directories())
+            .chain(self.upload_dir.clone())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Query string of the gallery tiles: a pre-warmed size if one is allowed, so tiles are
+    /// ready at startup, else the smallest allowed size or a full size compressed image.
+    pub fn thumbnail_query(&self) -> String {
+        self.prewarm_sizes
+            .iter()
+            .find(|size| self.allowed_sizes.contains(size))
+            .or_else(|| self.allowed_sizes.iter().min())
+            .map(|size| format!("w={}", size))
+            .unwrap_or_else(|| "compress".to_owned())
+    }
+
     pub fn check(&self) -> anyhow::Result<(String, String)> {
         let cert_missing = self.cert.is_none();
         let key_missing = self.key.is_none();
@@ -53,6 +269,36 @@ impl AppConfig {
     }
 }
 
+/// Collection names end up in URLs, route patterns and file names, so they are limited to
+/// ASCII letters, digits, `-` and `_`. That also rules out `.` and `..` as directories.
+fn check_collection_name(name: &str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid || RESERVED_COLLECTIONS.contains(&name) {
+        anyhow::bail!("{:?} cannot be used as a collection name", name);
+    }
+    Ok(())
+}
+
+/// `/etc/jorge-a-day/index.json` becomes `/etc/jorge-a-day/index-luna.json` for the luna collection
+fn collection_file(path: &str, name: &str) -> String {
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, name, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, name),
+    };
+
+    path.with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
 // Takes in the certificate and key and generates an openssl instance.
 // Most likely fail cause is missing certificates or incorrect permissions.
 pub fn create_ssl_builder(
@@ -75,3 +321,77 @@ pub fn create_ssl_builder(
 
     Ok(builder)
 }
+
+#[cfg(test)]
+impl AppConfig {
+    /// The defaults with images, state files and derivatives all inside `dir`
+    pub fn test_in(dir: &Path) -> AppConfig {
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::create_dir_all(dir.join("images")).unwrap();
+
+        AppConfig {
+            directories: Some(vec![path("images")]),
+            index_path: path("index.json"),
+            hidden_path: path("hidden.json"),
+            captions_path: path("captions.json"),
+            daily_schedule_path: path("schedule.json"),
+            daily_state_path: path("daily.json"),
+            derivative_dir: path("derivatives"),
+            trash_dir: path("trash"),
+            ..AppConfig::builder().load().unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_collection_names() {
+        for name in ["luna", "Luna_2", "jorge-a-day"] {
+            assert!(check_collection_name(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_unsafe_collection_names() {
+        for name in ["", ".", "..", "{x}", "a/b", "a.b", "luna cat", "ä"] {
+            assert!(check_collection_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_collections_with_missing_directories() {
+        let dir = std::env::temp_dir().join(format!("jorge-config-{}", std::process::id()));
+        let collection = |directory: &Path| CollectionConfig {
+            directories: vec![directory.to_string_lossy().into_owned()],
+            title: None,
+            daily_policy: None,
+            upload_dir: None,
+        };
+        let config = AppConfig {
+            collections: BTreeMap::from([
+                ("luna".to_owned(), collection(&dir.join("images"))),
+                ("typo".to_owned(), collection(&dir.join("imagse"))),
+            ]),
+            ..AppConfig::test_in(&dir)
+        };
+
+        assert!(config.for_collection("luna").is_ok());
+        let error = config.for_collection("typo").err().unwrap().to_string();
+        assert!(
+            error.contains("imagse") && error.contains("typo"),
+            "{}",
+            error
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_reserved_collection_names() {
+        for name in RESERVED_COLLECTIONS {
+            assert!(check_collection_name(name).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::config::AppConfig;
+use actix_web::{
+    FromRequest, HttpRequest, HttpResponse,
+    dev::Payload,
+    error::{Error, InternalError},
+    http::header,
+    web,
+};
+use sha2::{Digest, Sha256};
+use std::future::{Ready, ready};
+
+/// Proof that a request carried one of the configured API tokens as a bearer token
+pub struct ApiToken;
+
+impl ApiToken {
+    fn check(req: &HttpRequest) -> bool {
+        let Some(config) = req.app_data::<web::Data<AppConfig>>() else {
+            return false;
+        };
+        let Some(token) = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+
+        // Digests have a fixed length, so comparing them does not leak how long a token is
+        let digest = Sha256::digest(token.trim());
+        config
+            .api_tokens
+            .iter()
+            .any(|allowed| Sha256::digest(allowed) == digest)
+    }
+}
+
+impl FromRequest for ApiToken {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        if Self::check(req) {
+            return ready(Ok(ApiToken));
+        }
+
+        let response = HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish();
+        ready(Err(InternalError::from_response(
+            "Invalid API token",
+            response,
+        )
+        .into()))
+    }
+}
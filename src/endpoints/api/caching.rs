@@ -0,0 +1,177 @@
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+    http::header::{
+        CacheControl, CacheDirective, ETag, EntityTag, Expires, HttpDate, IfModifiedSince,
+        IfNoneMatch, LastModified,
+    },
+};
+use chrono::{DateTime, Utc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Validators used for conditional requests
+pub struct Validators {
+    pub etag: EntityTag,
+    pub last_modified: DateTime<Utc>,
+    pub max_age: u32,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    pub fn new(tag: String, last_modified: DateTime<Utc>, max_age: u32) -> Self {
+        Self {
+            etag: EntityTag::new_strong(tag),
+            last_modified,
+            max_age,
+            expires: None,
+        }
+    }
+
+    /// Stops caches from keeping the response past `expires`
+    pub fn expiring_at(mut self, expires: DateTime<Utc>) -> Self {
+        let remaining = (expires - Utc::now()).num_seconds().max(0);
+        self.max_age = self.max_age.min(remaining as u32);
+        self.expires = Some(expires);
+        self
+    }
+
+    fn last_modified_time(&self) -> SystemTime {
+        to_system_time(self.last_modified)
+    }
+
+    pub fn last_modified_http_date(&self) -> HttpDate {
+        HttpDate::from(self.last_modified_time())
+    }
+
+    /// Whether the client already holds the current representation
+    pub fn is_fresh(&self, req: &HttpRequest) -> bool {
+        // If-None-Match takes precedence over If-Modified-Since
+        if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+            return match if_none_match {
+                IfNoneMatch::Any => true,
+                IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(&self.etag)),
+            };
+        }
+
+        if let Some(IfModifiedSince(since)) = req.get_header::<IfModifiedSince>() {
+            return self.last_modified_time() <= SystemTime::from(since);
+        }
+
+        false
+    }
+
+    /// Adds ETag, Last-Modified, Cache-Control and Expires to a response
+    pub fn apply(&self, builder: &mut HttpResponseBuilder) {
+        builder
+            .insert_header(ETag(self.etag.clone()))
+            .insert_header(LastModified(self.last_modified_http_date()))
+            .insert_header(CacheControl(vec![
+                CacheDirective::Public,
+                CacheDirective::MaxAge(self.max_age),
+            ]));
+        if let Some(expires) = self.expires {
+            builder.insert_header(Expires(HttpDate::from(to_system_time(expires))));
+        }
+    }
+
+    pub fn not_modified(&self) -> HttpResponse {
+        let mut response = HttpResponse::NotModified();
+        self.apply(&mut response);
+        response.finish()
+    }
+}
+
+/// HTTP dates only carry whole seconds
+fn to_system_time(time: DateTime<Utc>) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(time.timestamp().max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, test::TestRequest};
+    use chrono::{TimeDelta, TimeZone};
+
+    fn validators() -> Validators {
+        // Half a second past the full second HTTP dates can express
+        let modified =
+            Utc.with_ymd_and_hms(2026, 3, 1, 12, 0, 0).unwrap() + TimeDelta::milliseconds(500);
+        Validators::new("abc".to_owned(), modified, 3600)
+    }
+
+    fn is_fresh(headers: &[(header::HeaderName, &str)]) -> bool {
+        let req = headers
+            .iter()
+            .fold(TestRequest::default(), |req, (name, value)| {
+                req.insert_header((name.clone(), *value))
+            })
+            .to_http_request();
+        validators().is_fresh(&req)
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        assert!(!is_fresh(&[]));
+        assert!(is_fresh(&[(header::IF_NONE_MATCH, "\"abc\"")]));
+        assert!(is_fresh(&[(header::IF_NONE_MATCH, "W/\"abc\"")]));
+        assert!(is_fresh(&[(header::IF_NONE_MATCH, "\"other\", \"abc\"")]));
+        assert!(is_fresh(&[(header::IF_NONE_MATCH, "*")]));
+        assert!(!is_fresh(&[(header::IF_NONE_MATCH, "\"other\"")]));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let later = "Mon, 02 Mar 2026 12:00:00 GMT";
+        assert!(!is_fresh(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, later),
+        ]));
+        assert!(is_fresh(&[
+            (header::IF_NONE_MATCH, "\"abc\""),
+            (header::IF_MODIFIED_SINCE, "Sat, 01 Jan 2000 00:00:00 GMT"),
+        ]));
+    }
+
+    #[test]
+    fn compares_modification_dates_in_whole_seconds() {
+        assert!(is_fresh(&[(
+            header::IF_MODIFIED_SINCE,
+            "Sun, 01 Mar 2026 12:00:00 GMT"
+        )]));
+        assert!(is_fresh(&[(
+            header::IF_MODIFIED_SINCE,
+            "Sun, 01 Mar 2026 12:00:01 GMT"
+        )]));
+        assert!(!is_fresh(&[(
+            header::IF_MODIFIED_SINCE,
+            "Sun, 01 Mar 2026 11:59:59 GMT"
+        )]));
+    }
+
+    #[test]
+    fn expiry_caps_max_age() {
+        let expiring = validators().expiring_at(Utc::now() + TimeDelta::seconds(60));
+        assert!(expiring.max_age <= 60 && expiring.max_age >= 59);
+
+        let expired = validators().expiring_at(Utc::now() - TimeDelta::seconds(60));
+        assert_eq!(expired.max_age, 0);
+
+        // An expiry further away than max-age leaves it alone
+        let distant = validators().expiring_at(Utc::now() + TimeDelta::days(1));
+        assert_eq!(distant.max_age, 3600);
+
+        let mut response = HttpResponse::Ok();
+        expired.apply(&mut response);
+        let response = response.finish();
+        let headers = response.headers();
+        assert_eq!(headers.get(header::ETAG).unwrap(), "\"abc\"");
+        assert_eq!(
+            headers.get(header::LAST_MODIFIED).unwrap(),
+            "Sun, 01 Mar 2026 12:00:00 GMT"
+        );
+        assert_eq!(
+            headers.get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=0"
+        );
+        assert!(headers.contains_key(header::EXPIRES));
+    }
+}
@@ -0,0 +1,224 @@
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse,
+    body::SizedStream,
+    http::header::{self, ContentRange, ContentRangeSpec, IfRange, Range},
+};
+use std::{io::SeekFrom, path::PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use super::caching::Validators;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    /// Inclusive first and last byte
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Works out which part of a `full_length` byte representation the client asked for.
+pub fn requested_range(
+    req: &HttpRequest,
+    validators: &Validators,
+    full_length: u64,
+) -> RangeRequest {
+    let Some(Range::Bytes(ranges)) = req.get_header::<Range>() else {
+        return RangeRequest::Full;
+    };
+
+    // Ranges only apply if the client still holds the representation it names
+    if let Some(if_range) = req.get_header::<IfRange>() {
+        let matches = match if_range {
+            IfRange::EntityTag(tag) => tag.strong_eq(&validators.etag),
+            IfRange::Date(date) => validators.last_modified_http_date() == date,
+        };
+        if !matches {
+            return RangeRequest::Full;
+        }
+    }
+
+    // Multiple ranges are rare enough to just serve the whole file
+    match ranges.as_slice() {
+        [range] => match range.to_satisfiable_range(full_length) {
+            Some((start, end)) => RangeRequest::Partial(start, end),
+            None => RangeRequest::Unsatisfiable,
+        },
+        _ => RangeRequest::Full,
+    }
+}
+
+/// Streams `length` bytes of a file starting at `start`
+pub async fn stream_file(
+    path: &PathBuf,
+    start: u64,
+    length: u64,
+) -> std::io::Result<SizedStream<ReaderStream<tokio::io::Take<tokio::fs::File>>>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+
+    Ok(SizedStream::new(
+        length,
+        ReaderStream::new(file.take(length)),
+    ))
+}
+
+/// Builds a 206 response streaming the inclusive byte range `start..=end` of an original.
+pub async fn partial_response(
+    path: &PathBuf,
+    content_type: String,
+    validators: &Validators,
+    (start, end): (u64, u64),
+    full_length: u64,
+) -> std::io::Result<HttpResponse> {
+    let mut response = HttpResponse::PartialContent();
+    validators.apply(&mut response);
+    response
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(ContentRange(ContentRangeSpec::Bytes {
+            range: Some((start, end)),
+            instance_length: Some(full_length),
+        }));
+
+    let body = stream_file(path, start, end - start + 1).await?;
+    Ok(response.content_type(content_type).body(body))
+}
+
+pub fn unsatisfiable_response(full_length: u64) -> HttpResponse {
+    HttpResponse::RangeNotSatisfiable()
+        .insert_header(ContentRange(ContentRangeSpec::Bytes {
+            range: None,
+            instance_length: Some(full_length),
+        }))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::to_bytes, http::StatusCode, test::TestRequest};
+    use chrono::{TimeZone, Utc};
+
+    const LENGTH: u64 = 1000;
+
+    fn validators() -> Validators {
+        let modified = Utc.with_ymd_and_hms(2026, 3, 1, 12, 0, 0).unwrap();
+        Validators::new("abc".to_owned(), modified, 60)
+    }
+
+    fn range_for(headers: &[(header::HeaderName, &str)]) -> RangeRequest {
+        let req = headers
+            .iter()
+            .fold(TestRequest::default(), |req, (name, value)| {
+                req.insert_header((name.clone(), *value))
+            })
+            .to_http_request();
+        requested_range(&req, &validators(), LENGTH)
+    }
+
+    #[test]
+    fn serves_single_ranges() {
+        assert_eq!(range_for(&[]), RangeRequest::Full);
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=0-99")]),
+            RangeRequest::Partial(0, 99)
+        );
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=900-")]),
+            RangeRequest::Partial(900, 999)
+        );
+        // Ranges running past the end are cut short
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=990-2000")]),
+            RangeRequest::Partial(990, 999)
+        );
+    }
+
+    #[test]
+    fn serves_suffix_ranges() {
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=-100")]),
+            RangeRequest::Partial(900, 999)
+        );
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=-5000")]),
+            RangeRequest::Partial(0, 999)
+        );
+    }
+
+    #[test]
+    fn serves_multiple_ranges_in_full() {
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=0-9,20-29")]),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn refuses_ranges_past_the_end() {
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=1000-")]),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            range_for(&[(header::RANGE, "bytes=-0")]),
+            RangeRequest::Unsatisfiable
+        );
+
+        let response = unsatisfiable_response(LENGTH);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            response.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes */1000"
+        );
+    }
+
+    #[test]
+    fn follows_if_range() {
+        let date = validators().last_modified_http_date().to_string();
+        for if_range in ["\"abc\"", date.as_str()] {
+            assert_eq!(
+                range_for(&[(header::RANGE, "bytes=0-99"), (header::IF_RANGE, if_range)]),
+                RangeRequest::Partial(0, 99),
+                "{}",
+                if_range
+            );
+        }
+
+        for if_range in ["\"other\"", "W/\"abc\"", "Sun, 01 Mar 2026 12:00:01 GMT"] {
+            assert_eq!(
+                range_for(&[(header::RANGE, "bytes=0-99"), (header::IF_RANGE, if_range)]),
+                RangeRequest::Full,
+                "{}",
+                if_range
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn streams_the_requested_bytes() {
+        let path = std::env::temp_dir().join(format!("jorge-range-{}", std::process::id()));
+        let data: Vec<u8> = (0..LENGTH).map(|byte| byte as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let response = partial_response(
+            &path,
+            "image/png".to_owned(),
+            &validators(),
+            (900, 999),
+            LENGTH,
+        )
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 900-999/1000"
+        );
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), "\"abc\"");
+        let body = to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, data[900..]);
+    }
+}
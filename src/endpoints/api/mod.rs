@@ -1,2 +1,5 @@
+pub mod auth;
+pub mod caching;
+pub mod files;
 pub mod routes;
 pub mod schema;
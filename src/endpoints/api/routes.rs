@@ -1,18 +1,177 @@
-use std::sync::Arc;
-
-use super::schema::CompressQuery;
-use crate::image_cache::cache::Cache;
+use super::auth::ApiToken;
+use super::caching::Validators;
+use super::files::{self, RangeRequest};
+use super::schema::{
+    Album, Albums, DailyImage, DailyPick, DerivativeUrl, HistoryQuery, ImageJson, ImageQuery,
+    Images, ImagesQuery,
+};
+use crate::endpoints::Collection;
+use crate::image_cache::cache::{Cache, UploadsDisabled};
+use crate::image_cache::captions::Caption;
+use crate::image_cache::encoder::EncoderBusy;
+use crate::image_cache::image::{Derivative, Image, OutputFormat, UnsupportedImage};
+use crate::image_cache::listing::{self, Cursor, Listing, SortOrder};
+use crate::image_cache::privacy::Stripping;
+use crate::image_cache::tags;
 use crate::{cache::CacheTrait, config::AppConfig};
-use actix_web::{HttpRequest, HttpResponse, Responder, get, http::header::ContentType, web};
+use actix_multipart::Multipart;
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, delete,
+    error::UrlGenerationError,
+    get,
+    http::header::{self, Accept, ContentType, Quality},
+    mime, post, put, web,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::StreamExt;
 use log::error;
-use tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+
+/// How long clients should wait before retrying when all encoders are busy
+const ENCODER_RETRY_AFTER_SECS: u32 = 5;
+
+/// Like `HttpRequest::url_for`, but stays inside the collection the request is for.
+/// Every collection registers the same route names, so actix resolves them to the top level.
+fn url_for<const N: usize>(
+    req: &HttpRequest,
+    name: &str,
+    elements: [&String; N],
+) -> Result<String, UrlGenerationError> {
+    let mut url = req.url_for(name, elements)?;
+    if let Some(collection) = req.app_data::<web::Data<Collection>>() {
+        let path = format!("/{}{}", collection.name, url.path());
+        url.set_path(&path);
+    }
+    Ok(url.to_string())
+}
+
+/// Picks the modern format the client advertises with the highest quality in its Accept
+/// header, AVIF on a tie. Formats with a quality of zero are refused.
+fn preferred_format(req: &HttpRequest) -> Option<OutputFormat> {
+    let accept = req.get_header::<Accept>()?;
+    // A format listed more than once counts with its lowest quality, so q=0 always refuses it
+    let quality = |format: OutputFormat| {
+        accept
+            .iter()
+            .filter(|item| item.item.essence_str() == format.content_type())
+            .map(|item| item.quality)
+            .min()
+    };
+
+    [OutputFormat::WebP, OutputFormat::Avif]
+        .into_iter()
+        .filter_map(|format| Some((format, quality(format)?)))
+        .filter(|(_, quality)| *quality > Quality::ZERO)
+        // The last of equal maximums wins, which is AVIF
+        .max_by_key(|(_, quality)| *quality)
+        .map(|(format, _)| format)
+}
+
+/// Originals whose metadata can't be rewritten are served re-encoded at full size instead
+fn reencoded_original(req: &HttpRequest, image: &Image, config: &AppConfig) -> Option<Derivative> {
+    (config.strip_metadata && image.stripping() == Stripping::Reencode).then(|| Derivative {
+        format: preferred_format(req).unwrap_or(OutputFormat::Jpeg),
+        resize: None,
+    })
+}
+
+/// Originals change when their metadata is stripped, so they need their own tag
+fn original_tag(req: &HttpRequest, image: &Image, config: &AppConfig) -> String {
+    if let Some(derivative) = reencoded_original(req, image, config) {
+        return format!("{}-{}", image.hash, derivative.variant());
+    }
+
+    if config.strip_metadata {
+        format!("{}-stripped", image.hash)
+    } else {
+        image.hash.clone()
+    }
+}
+
+/// Last-Modified of the daily image on `date`. The pick may be an older file than the one
+/// shown the day before, so it never predates the start of the day.
+fn daily_modified(cache: &Cache, image: &Image, date: NaiveDate) -> DateTime<Utc> {
+    cache
+        .start_of_day(date)
+        .map_or(image.modified, |start| start.max(image.modified))
+}
+
+/// Serves a daily image, answering conditional requests first
+async fn daily_response(
+    req: &HttpRequest,
+    config: &AppConfig,
+    cache: &Cache,
+    image: &Image,
+    validators: Validators,
+) -> HttpResponse {
+    let reencoded = reencoded_original(req, image, config);
+    // The format of a re-encoded original depends on Accept, 304s included
+    let vary = |response: &mut HttpResponseBuilder| {
+        if reencoded.is_some() {
+            response.insert_header((header::VARY, "Accept"));
+        }
+    };
+
+    if validators.is_fresh(req) {
+        let mut response = HttpResponse::NotModified();
+        validators.apply(&mut response);
+        vary(&mut response);
+        return response.finish();
+    }
+
+    let mut response = HttpResponse::Ok();
+    validators.apply(&mut response);
+    vary(&mut response);
+
+    if let Some(derivative) = reencoded {
+        return match cache.get_data_bytes(&image.hash, &derivative).await {
+            Ok((content_type, data)) => response.content_type(content_type).body(data),
+            Err(e) if e.is::<EncoderBusy>() => HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, ENCODER_RETRY_AFTER_SECS.to_string()))
+                .body("Server is busy encoding images"),
+            Err(e) => {
+                error!("Error serving daily image {:?}", e);
+                HttpResponse::NotFound().finish()
+            }
+        };
+    }
+
+    let original = if config.strip_metadata {
+        cache.stripped_original(image).await
+    } else {
+        Ok((image.path.clone(), image.size))
+    };
+    let result = match original {
+        Ok((path, size)) => files::stream_file(&path, 0, size)
+            .await
+            .map(|body| response.content_type(image.content_type()).body(body))
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e),
+    };
+
+    result.unwrap_or_else(|e| {
+        error!("Error serving daily image {:?}", e);
+        HttpResponse::NotFound().finish()
+    })
+}
 
 #[get("/daily")]
-async fn daily(_: web::Data<AppConfig>, cache: web::Data<Arc<Mutex<Cache>>>) -> impl Responder {
-    match cache.lock().await.get_newest_image().await {
-        Some(image) => HttpResponse::Ok()
-            .content_type(image.content_type())
-            .body(image.data),
+async fn daily(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+) -> impl Responder {
+    let daily_image = cache.get_daily_image().await;
+    match daily_image {
+        Some(image) => {
+            let validators = Validators::new(
+                original_tag(&req, &image, &config),
+                daily_modified(&cache, &image, cache.today()),
+                config.daily_max_age,
+            )
+            .expiring_at(cache.next_daily_rollover());
+            daily_response(&req, &config, &cache, &image, validators).await
+        }
         None => {
             error!("Daily image is missing?");
             HttpResponse::NotFound().finish()
@@ -20,37 +179,682 @@ async fn daily(_: web::Data<AppConfig>, cache: web::Data<Arc<Mutex<Cache>>>) ->
     }
 }
 
+#[get("/daily.json")]
+async fn daily_json(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+) -> impl Responder {
+    let Some(image) = cache.get_daily_image().await else {
+        error!("Daily image is missing?");
+        return HttpResponse::NotFound().finish();
+    };
+
+    let today = cache.today();
+    let url = match url_for(&req, "get_image", [&image.hash]) {
+        Ok(url) => url,
+        Err(e) => {
+            error!("Error building daily image URL {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let sizes = config.allowed_sizes.iter().map(|&size| DerivativeUrl {
+        width: Some(size),
+        url: format!("{}?w={}", url, size),
+    });
+    let derivatives = std::iter::once(DerivativeUrl {
+        width: None,
+        url: format!("{}?compress", url),
+    })
+    .chain(sizes)
+    .collect();
+
+    let daily_image = DailyImage {
+        id: image.hash.clone(),
+        date: today,
+        width: image.width,
+        height: image.height,
+        derivatives,
+        image: ImageJson::new(url, &image, &config.exif_fields),
+    };
+    let body = match serde_json::to_vec(&daily_image) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error serializing daily image {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Captions and tags change without touching the image, so the tag covers the whole body
+    let validators = Validators::new(
+        format!("{:x}", Sha256::digest(&body)),
+        daily_modified(&cache, &image, today),
+        config.daily_max_age,
+    )
+    .expiring_at(cache.next_daily_rollover());
+    if validators.is_fresh(&req) {
+        return validators.not_modified();
+    }
+
+    let mut response = HttpResponse::Ok();
+    validators.apply(&mut response);
+    response.content_type(ContentType::json()).body(body)
+}
+
+#[get("/daily/history")]
+async fn daily_history(
+    req: HttpRequest,
+    cache: web::Data<Cache>,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    let from = query.from.unwrap_or(NaiveDate::MIN);
+    let to = query.to.unwrap_or_else(|| cache.today());
+
+    let picks: Vec<DailyPick> = cache
+        .get_daily_history(from, to)
+        .await
+        .into_iter()
+        .filter_map(|(date, id)| {
+            let url = url_for(&req, "get_image", [&id]).ok()?;
+            Some(DailyPick { date, id, url })
+        })
+        .collect();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(picks)
+}
+
+#[get("/daily/{date}")]
+async fn daily_on(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+    path: web::Path<NaiveDate>,
+) -> impl Responder {
+    let date = path.into_inner();
+    let Some(image) = cache.get_daily_image_on(date).await else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    // Past picks never change, today's may until the next rollover
+    let validators = if date == cache.today() {
+        Validators::new(
+            original_tag(&req, &image, &config),
+            daily_modified(&cache, &image, date),
+            config.daily_max_age,
+        )
+        .expiring_at(cache.next_daily_rollover())
+    } else {
+        Validators::new(
+            original_tag(&req, &image, &config),
+            daily_modified(&cache, &image, date),
+            config.original_max_age,
+        )
+    };
+    daily_response(&req, &config, &cache, &image, validators).await
+}
+
+#[get("/stats")]
+async fn stats(_token: ApiToken, cache: web::Data<Cache>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(cache.stats())
+}
+
+#[get("/admin/warmup")]
+async fn warmup(_token: ApiToken, cache: web::Data<Cache>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(cache.warmup_progress())
+}
+
+/// One page of the images with one of `tags`, or of all images if it is empty
+fn image_listing(
+    req: &HttpRequest,
+    config: &AppConfig,
+    cache: &Cache,
+    query: &ImagesQuery,
+    tags: Vec<String>,
+) -> HttpResponse {
+    let cursor = match query
+        .cursor
+        .as_deref()
+        .map(str::parse::<Cursor>)
+        .transpose()
+    {
+        Ok(cursor) => cursor,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid cursor"),
+    };
+
+    // Random listings get a seed if they lack one, the next link carries it along
+    let sort = query.sort.unwrap_or_default();
+    let seed = query.seed.unwrap_or_else(rand::random);
+    let limit = listing::page_size(query.limit);
+    let listing = Listing {
+        sort,
+        seed,
+        from: query.from.and_then(|date| cache.start_of_day(date)),
+        until: query
+            .to
+            .and_then(|date| date.succ_opt())
+            .and_then(|date| cache.start_of_day(date)),
+        tags,
+        cursor,
+        limit,
+    };
+    let (page, next_cursor) = cache.get_image_page(&listing);
+
+    let mut url = req.full_url();
+    url.set_query(None);
+
+    let next = next_cursor.map(|cursor| {
+        let mut next = url.clone();
+        let mut pairs = next.query_pairs_mut();
+        pairs
+            .append_pair("limit", &limit.to_string())
+            .append_pair("sort", sort.as_str());
+        if sort == SortOrder::Random {
+            pairs.append_pair("seed", &seed.to_string());
+        }
+        if let Some(from) = query.from {
+            pairs.append_pair("from", &from.to_string());
+        }
+        if let Some(to) = query.to {
+            pairs.append_pair("to", &to.to_string());
+        }
+        if let Some(tag) = &query.tag {
+            pairs.append_pair("tag", tag);
+        }
+        pairs.append_pair("cursor", &cursor.to_string());
+        drop(pairs);
+        next.to_string()
+    });
+
+    let images: Result<Vec<ImageJson>, _> = page
+        .into_iter()
+        .map(|(id, img)| {
+            url_for(req, "get_image", [&id])
+                .map(|url| ImageJson::new(url, &img, &config.exif_fields))
+        })
+        .collect();
+
+    match images {
+        Ok(images) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(Images { images, next }),
+        Err(e) => {
+            error!("Error building image URL {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 #[get("/images")]
-async fn list_images(req: HttpRequest, cache: web::Data<Arc<Mutex<Cache>>>) -> impl Responder {
-    let domain = req.full_url();
+async fn list_images(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+    query: web::Query<ImagesQuery>,
+) -> impl Responder {
+    let tags = tags::normalize(&query.tag);
+    image_listing(&req, &config, &cache, &query, tags)
+}
+
+#[get("/albums")]
+async fn list_albums(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+) -> impl Responder {
+    let albums: Result<Vec<Album>, _> = config
+        .albums
+        .iter()
+        .map(|(name, tags)| {
+            let tags = tags::normalize(tags);
+            let (count, newest) = cache.tag_summary(&tags);
+            let cover = newest
+                .map(|id| url_for(&req, "get_image", [&id]))
+                .transpose()?;
+
+            Ok::<Album, UrlGenerationError>(Album {
+                name: name.to_owned(),
+                url: url_for(&req, "get_album", [name])?,
+                tags,
+                count,
+                cover,
+            })
+        })
+        .collect();
+
+    match albums {
+        Ok(albums) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(Albums { albums }),
+        Err(e) => {
+            error!("Error building album URL {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/albums/{name}")]
+async fn get_album(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+    path: web::Path<String>,
+    query: web::Query<ImagesQuery>,
+) -> impl Responder {
+    let Some(tags) = config.albums.get(path.as_str()) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    image_listing(&req, &config, &cache, &query, tags::normalize(tags))
+}
+
+/// Reads the uploaded file from a multipart form or, failing that, the raw body
+async fn read_upload(
+    req: &HttpRequest,
+    payload: web::Payload,
+    limit: usize,
+) -> Result<Vec<u8>, HttpResponse> {
+    let too_large = || HttpResponse::PayloadTooLarge().body("Upload is too large");
+    let is_multipart = req
+        .mime_type()
+        .ok()
+        .flatten()
+        .is_some_and(|mime| mime.essence_str() == mime::MULTIPART_FORM_DATA.essence_str());
 
-    let images = cache.lock().await.get_images(&domain.to_string()).await;
+    if !is_multipart {
+        return payload
+            .to_bytes_limited(limit)
+            .await
+            .map_err(|_| too_large())?
+            .map(|body| body.to_vec())
+            .map_err(|_| HttpResponse::BadRequest().body("Invalid upload body"));
+    }
+
+    let mut multipart = Multipart::new(req.headers(), payload);
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|_| HttpResponse::BadRequest().body("Invalid form data"))?;
+        if field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .is_none()
+        {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|_| HttpResponse::BadRequest().body("Invalid form data"))?;
+            if data.len() + chunk.len() > limit {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        return Ok(data);
+    }
+
+    Err(HttpResponse::BadRequest().body("Form has no file"))
+}
+
+#[post("/images")]
+async fn upload_image(
+    _token: ApiToken,
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+    payload: web::Payload,
+) -> impl Responder {
+    let limit = config.max_upload_mb.saturating_mul(1024 * 1024);
+    let data = match read_upload(&req, payload, limit).await {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+
+    let (id, created) = match cache.insert_upload(data).await {
+        Ok(inserted) => inserted,
+        Err(e) if e.is::<UnsupportedImage>() => {
+            return HttpResponse::UnsupportedMediaType().body(e.to_string());
+        }
+        Err(e) if e.is::<UploadsDisabled>() => {
+            return HttpResponse::ServiceUnavailable().body(e.to_string());
+        }
+        Err(e) => {
+            error!("Error storing upload {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let Some(image) = cache.get_image(&id) else {
+        return HttpResponse::Conflict().body("Image exists but is hidden");
+    };
+    let Ok(url) = url_for(&req, "get_image", [&id]) else {
+        error!("Error building image URL for {}", id);
+        return HttpResponse::InternalServerError().finish();
+    };
+
+    let mut response = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header((header::LOCATION, url.as_str()))
+        .content_type(ContentType::json())
+        .json(ImageJson::new(url, &image, &config.exif_fields))
+}
+
+#[delete("/images/{id}")]
+async fn delete_image(
+    _token: ApiToken,
+    cache: web::Data<Cache>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match cache.delete_image(&path.into_inner()).await {
+        Ok(Some(_)) => HttpResponse::NoContent().finish(),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Error deleting image {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/images/{id}/hide")]
+async fn hide_image(
+    _token: ApiToken,
+    cache: web::Data<Cache>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if cache.hide_image(&path.into_inner()) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[post("/images/{id}/unhide")]
+async fn unhide_image(
+    _token: ApiToken,
+    cache: web::Data<Cache>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if cache.unhide_image(&path.into_inner()) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[put("/images/{id}/caption")]
+async fn set_caption(
+    _token: ApiToken,
+    cache: web::Data<Cache>,
+    path: web::Path<String>,
+    caption: web::Json<Caption>,
+) -> impl Responder {
+    if cache.set_caption(&path.into_inner(), caption.into_inner()) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[get("/admin/hidden")]
+async fn hidden_images(_token: ApiToken, cache: web::Data<Cache>) -> impl Responder {
     HttpResponse::Ok()
         .content_type(ContentType::json())
-        .json(images)
+        .json(cache.hidden_images())
+}
+
+#[get("/images/{id}/meta")]
+async fn image_meta(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(image) = cache.get_image(&id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    match url_for(&req, "get_image", [&id]) {
+        Ok(url) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(ImageJson::new(url, &image, &config.exif_fields)),
+        Err(e) => {
+            error!("Error building image URL {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
 
 #[get("/images/{id}")]
 async fn get_image(
-    cache: web::Data<Arc<Mutex<Cache>>>,
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
     path: web::Path<String>,
-    query: web::Query<CompressQuery>,
+    query: web::Query<ImageQuery>,
 ) -> impl Responder {
     let image_path = path.into_inner();
     let compressed = query.compress.clone().map(|_| true).unwrap_or(false);
+    let resize = query.resize();
 
-    match cache
-        .lock()
-        .await
-        .get_data_bytes(&image_path, compressed)
-        .await
-    {
-        Ok((content_type, image)) => {
-            return HttpResponse::Ok().content_type(content_type).body(image);
+    if let Some(resize) = &resize {
+        let allowed = [resize.width, resize.height]
+            .into_iter()
+            .flatten()
+            .all(|size| config.allowed_sizes.contains(&size));
+        if !allowed {
+            return HttpResponse::BadRequest().body("Requested size is not allowed");
         }
+    }
+
+    // Without AVIF/WebP support resized images fall back to JPEG and compressed ones to the original
+    let format = preferred_format(&req);
+    let derivative = match (format, resize) {
+        (Some(format), resize) if compressed || resize.is_some() => {
+            Some(Derivative { format, resize })
+        }
+        (None, Some(resize)) => Some(Derivative {
+            format: OutputFormat::Jpeg,
+            resize: Some(resize),
+        }),
+        _ => None,
+    };
+
+    let Some(image) = cache.get_image(&image_path) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let derivative = derivative.or_else(|| reencoded_original(&req, &image, &config));
+    let negotiated = compressed || resize.is_some() || derivative.is_some();
+
+    let validators = match &derivative {
+        Some(derivative) => Validators::new(
+            format!("{}-{}", image.hash, derivative.variant()),
+            image.modified,
+            config.derivative_max_age,
+        ),
+        None => Validators::new(
+            original_tag(&req, &image, &config),
+            image.modified,
+            config.original_max_age,
+        ),
+    };
+
+    let fresh = validators.is_fresh(&req);
+    let mut response = if fresh {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    validators.apply(&mut response);
+    if negotiated {
+        response.insert_header((header::VARY, "Accept"));
+    }
+    if fresh {
+        return response.finish();
+    }
+
+    // Originals, and their stripped copies, are streamed from disk and support Range requests
+    let Some(derivative) = derivative else {
+        let original = if config.strip_metadata {
+            cache.stripped_original(&image).await
+        } else {
+            Ok((image.path.clone(), image.size))
+        };
+        let (path, size) = match original {
+            Ok(original) => original,
+            Err(e) => {
+                error!("Error stripping metadata {:?}", e);
+                return HttpResponse::NotFound().finish();
+            }
+        };
+        let content_type = image.content_type();
+
+        response.insert_header((header::ACCEPT_RANGES, "bytes"));
+
+        let result = match files::requested_range(&req, &validators, size) {
+            RangeRequest::Full => files::stream_file(&path, 0, size)
+                .await
+                .map(|body| response.content_type(content_type).body(body)),
+            RangeRequest::Partial(start, end) => {
+                files::partial_response(&path, content_type, &validators, (start, end), size).await
+            }
+            RangeRequest::Unsatisfiable => return files::unsatisfiable_response(size),
+        };
+
+        return result.unwrap_or_else(|e| {
+            error!("Error with requested file {:?}", e);
+            HttpResponse::NotFound().finish()
+        });
+    };
+
+    let result = cache.get_data_bytes(&image_path, &derivative).await;
+
+    match result {
+        Ok((content_type, image)) => response.content_type(content_type).body(image),
+        Err(e) if e.is::<EncoderBusy>() => HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, ENCODER_RETRY_AFTER_SECS.to_string()))
+            .body("Server is busy encoding images"),
         Err(e) => {
             error!("Error with requested file {:?}", e);
-            return HttpResponse::NotFound().finish();
+            HttpResponse::NotFound().finish()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{FromRequest, http::StatusCode, test::TestRequest};
+
+    fn format_for(accept: &str) -> Option<OutputFormat> {
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT, accept))
+            .to_http_request();
+        preferred_format(&req)
+    }
+
+    #[test]
+    fn prefers_avif_on_equal_quality() {
+        assert_eq!(
+            format_for("image/avif,image/webp,*/*"),
+            Some(OutputFormat::Avif)
+        );
+        assert_eq!(
+            format_for("image/webp,image/avif;q=1.0"),
+            Some(OutputFormat::Avif)
+        );
+    }
+
+    #[test]
+    fn follows_quality_values() {
+        assert_eq!(
+            format_for("image/avif;q=0.5,image/webp;q=0.8"),
+            Some(OutputFormat::WebP)
+        );
+        assert_eq!(
+            format_for("image/avif;q=0,image/webp"),
+            Some(OutputFormat::WebP)
+        );
+        assert_eq!(
+            format_for("image/avif,image/avif;q=0,image/webp;q=0.1"),
+            Some(OutputFormat::WebP)
+        );
+    }
+
+    #[test]
+    fn refuses_formats_with_zero_quality() {
+        assert_eq!(format_for("image/avif;q=0,image/webp;q=0"), None);
+        assert_eq!(format_for("image/*,*/*;q=0.8"), None);
+    }
+
+    async fn upload(req: TestRequest, limit: usize) -> Result<Vec<u8>, StatusCode> {
+        let (req, mut payload) = req.to_http_parts();
+        let payload = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        read_upload(&req, payload, limit)
+            .await
+            .map_err(|response| response.status())
+    }
+
+    fn form(parts: &[(&str, Option<&str>, &[u8])]) -> TestRequest {
+        let mut body = Vec::new();
+        for (name, filename, data) in parts {
+            let disposition = match filename {
+                Some(filename) => format!("; filename=\"{}\"", filename),
+                None => String::new(),
+            };
+            body.extend_from_slice(
+                format!(
+                    "--jorge\r\nContent-Disposition: form-data; name=\"{}\"{}\r\n\r\n",
+                    name, disposition
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--jorge--\r\n");
+
+        TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=jorge"))
+            .set_payload(body)
+    }
+
+    #[actix_web::test]
+    async fn reads_raw_uploads_up_to_the_limit() {
+        let data = vec![7; 100];
+        let req = || TestRequest::post().set_payload(data.clone());
+
+        assert_eq!(upload(req(), 100).await, Ok(data.clone()));
+        assert_eq!(upload(req(), 99).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[actix_web::test]
+    async fn reads_the_file_of_a_form() {
+        let parts: &[(&str, Option<&str>, &[u8])] = &[
+            ("title", None, b"Jorge"),
+            ("file", Some("jorge.png"), &[7; 100]),
+        ];
+
+        assert_eq!(upload(form(parts), 100).await, Ok(vec![7; 100]));
+        assert_eq!(
+            upload(form(parts), 99).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(
+            upload(form(&parts[..1]), 100).await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+}
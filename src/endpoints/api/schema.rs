@@ -1,33 +1,220 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 
-/// Return code for GET /daily
+use crate::image_cache::captions::Caption;
+use crate::image_cache::image::{Fit, Image, MetaField, Resize};
+use crate::image_cache::listing::SortOrder;
+
+/// Return code for GET /daily.json
 #[derive(Deserialize, Serialize)]
 pub struct DailyImage {
+    pub id: String,
+    pub date: NaiveDate,
     pub image: ImageJson,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub derivatives: Vec<DerivativeUrl>,
+}
+
+/// A compressed or resized version of an image, `width` is empty for the full size one
+#[derive(Deserialize, Serialize)]
+pub struct DerivativeUrl {
+    pub width: Option<u32>,
+    pub url: String,
 }
 
+/// Return code for GET /images
 #[derive(Deserialize, Serialize)]
 pub struct Images {
-    images: Vec<ImageJson>,
+    pub images: Vec<ImageJson>,
+    pub next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ImagesQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub sort: Option<SortOrder>,
+    pub seed: Option<u64>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub tag: Option<String>,
+}
+
+/// Return code for GET /albums
+#[derive(Deserialize, Serialize)]
+pub struct Albums {
+    pub albums: Vec<Album>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Album {
+    pub name: String,
+    pub url: String,
+    pub tags: Vec<String>,
+    pub count: usize,
+    /// URL of the newest image in the album
+    pub cover: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct ImageJson {
     pub date: DateTime<Utc>,
     pub url: String,
+    #[serde(flatten)]
+    pub caption: Caption,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub meta: ImageMeta,
 }
 
-impl From<(String, crate::image_cache::image::Image)> for ImageJson {
-    fn from((key, img): (String, crate::image_cache::image::Image)) -> Self {
+impl ImageJson {
+    pub fn new(url: String, img: &Image, fields: &[MetaField]) -> Self {
         Self {
-            url: key,
+            url,
             date: img.image_age,
+            caption: img.caption.clone(),
+            tags: img.tags.clone(),
+            meta: ImageMeta::new(img, fields),
         }
     }
+
+    /// Alt text for the gallery, falling back to the caption or title
+    pub fn alt_text(&self) -> &str {
+        [
+            &self.caption.alt,
+            &self.caption.caption,
+            &self.caption.title,
+        ]
+        .into_iter()
+        .flatten()
+        .next()
+        .map_or("It's Jorge!", String::as_str)
+    }
+
+    /// Title and shot details on a single line, for tooltips
+    pub fn tooltip(&self) -> String {
+        [self.caption.title.clone(), Some(self.meta.summary())]
+            .into_iter()
+            .flatten()
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<String>>()
+            .join(" - ")
+    }
+}
+
+/// Return code for GET /images/{id}/meta, only holds the fields allowed in the config
+#[derive(Default, Deserialize, Serialize)]
+pub struct ImageMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lens: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exposure: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focal_length: Option<String>,
+}
+
+impl ImageMeta {
+    pub fn new(img: &Image, fields: &[MetaField]) -> Self {
+        let allowed = |field: MetaField| fields.contains(&field);
+        let details = &img.details;
+
+        Self {
+            width: img.width.filter(|_| allowed(MetaField::Width)),
+            height: img.height.filter(|_| allowed(MetaField::Height)),
+            camera: details
+                .camera
+                .clone()
+                .filter(|_| allowed(MetaField::Camera)),
+            lens: details.lens.clone().filter(|_| allowed(MetaField::Lens)),
+            exposure: details
+                .exposure
+                .clone()
+                .filter(|_| allowed(MetaField::Exposure)),
+            iso: details.iso.filter(|_| allowed(MetaField::Iso)),
+            focal_length: details
+                .focal_length
+                .clone()
+                .filter(|_| allowed(MetaField::FocalLength)),
+        }
+    }
+
+    /// Shot details on a single line, e.g. "X-T3, 1/250 s, ISO 400, 35 mm"
+    pub fn summary(&self) -> String {
+        [
+            self.camera.clone(),
+            self.lens.clone(),
+            self.exposure.clone(),
+            self.iso.map(|iso| format!("ISO {}", iso)),
+            self.focal_length.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<String>>()
+        .join(", ")
+    }
+}
+
+/// Return code for GET /daily/history
+#[derive(Deserialize, Serialize)]
+pub struct DailyPick {
+    pub date: NaiveDate,
+    pub id: String,
+    pub url: String,
 }
 
 #[derive(Deserialize)]
-pub struct CompressQuery {
+pub struct HistoryQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+/// Return code for GET /stats
+#[derive(Deserialize, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub used_bytes: usize,
+    pub budget_bytes: usize,
+}
+
+/// Return code for GET /admin/warmup
+#[derive(Deserialize, Serialize)]
+pub struct WarmupProgress {
+    pub queued: u64,
+    pub completed: u64,
+    pub failed: u64,
+    pub pending: u64,
+}
+
+#[derive(Deserialize)]
+pub struct ImageQuery {
     pub compress: Option<String>,
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<Fit>,
+}
+
+impl ImageQuery {
+    pub fn resize(&self) -> Option<Resize> {
+        if self.w.is_none() && self.h.is_none() {
+            return None;
+        }
+
+        Some(Resize {
+            width: self.w,
+            height: self.h,
+            fit: self.fit.unwrap_or_default(),
+        })
+    }
 }
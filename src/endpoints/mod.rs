@@ -1,2 +1,34 @@
 pub mod api;
 pub mod ui;
+
+use actix_web::web;
+
+/// Name of the collection a scope serves, absent at the top level
+pub struct Collection {
+    pub name: String,
+}
+
+/// Registers every route, both at the top level and inside each collection
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(api::routes::daily)
+        .service(api::routes::daily_json)
+        .service(api::routes::daily_history)
+        .service(api::routes::daily_on)
+        .service(api::routes::get_image)
+        .service(api::routes::image_meta)
+        .service(api::routes::list_images)
+        .service(api::routes::list_albums)
+        .service(api::routes::get_album)
+        .service(api::routes::upload_image)
+        .service(api::routes::delete_image)
+        .service(api::routes::hide_image)
+        .service(api::routes::unhide_image)
+        .service(api::routes::set_caption)
+        .service(api::routes::hidden_images)
+        .service(api::routes::stats)
+        .service(api::routes::warmup)
+        .service(ui::routes::gallery)
+        .service(ui::routes::album)
+        .service(ui::routes::about)
+        .service(ui::routes::favicon);
+}
@@ -1,9 +1,22 @@
+use crate::endpoints::api::schema::ImageJson;
 use askama::Template;
 
 #[derive(Template)]
 #[template(path = "gallery.html.j2", ext = "html")]
 pub struct GalleryPage {
-    pub images: Vec<String>,
+    pub site_title: String,
+    pub title: String,
+    pub images: Vec<ImageJson>,
+    pub albums: Vec<AlbumLink>,
+    /// Link back to the full gallery, shown on album pages
+    pub home: Option<String>,
+    /// Query string added to image URLs for the tiles, e.g. `w=400`
+    pub thumbnail: String,
+}
+
+pub struct AlbumLink {
+    pub name: String,
+    pub url: String,
 }
 
 #[derive(Template)]
@@ -1,40 +1,77 @@
 use crate::{
     cache::CacheTrait,
-    endpoints::ui::pages::{AboutPage, GalleryPage},
-    image_cache::cache::Cache,
+    config::AppConfig,
+    endpoints::ui::pages::{AboutPage, AlbumLink, GalleryPage},
+    image_cache::{cache::Cache, tags},
 };
 use actix_web::{HttpResponse, Responder, get, web};
 use askama::Template;
-use std::sync::Arc;
 
-use tokio::sync::Mutex;
+/// Links to every album, relative to `prefix`
+fn album_links(config: &AppConfig, prefix: &str) -> Vec<AlbumLink> {
+    config
+        .albums
+        .keys()
+        .map(|name| AlbumLink {
+            name: name.to_owned(),
+            url: format!("{}{}", prefix, name),
+        })
+        .collect()
+}
 
-#[get("/")]
-async fn gallery(cache: web::Data<Arc<Mutex<Cache>>>) -> impl Responder {
-    let data: Vec<String> = cache
-        .lock()
-        .await
-        .get_images("images")
-        .await
-        .iter()
-        .map(|i| i.url.to_owned())
-        .collect();
-
-    let page = GalleryPage { images: data };
+fn render_gallery(page: GalleryPage) -> HttpResponse {
     match page.render() {
         Ok(page) => HttpResponse::Ok().body(page),
         Err(_) => HttpResponse::InternalServerError().body("Error templating gallery page"),
     }
 }
 
+#[get("/")]
+async fn gallery(config: web::Data<AppConfig>, cache: web::Data<Cache>) -> impl Responder {
+    let data = cache.get_images("images", &config.exif_fields, &[]).await;
+
+    render_gallery(GalleryPage {
+        site_title: config.title.clone(),
+        title: config.title.clone(),
+        images: data,
+        albums: album_links(&config, "album/"),
+        home: None,
+        thumbnail: config.thumbnail_query(),
+    })
+}
+
+#[get("/album/{name}")]
+async fn album(
+    config: web::Data<AppConfig>,
+    cache: web::Data<Cache>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let Some(tags) = config.albums.get(&name) else {
+        return HttpResponse::NotFound().body("No such album");
+    };
+
+    let data = cache
+        .get_images("../images", &config.exif_fields, &tags::normalize(tags))
+        .await;
+
+    render_gallery(GalleryPage {
+        site_title: config.title.clone(),
+        title: name,
+        images: data,
+        albums: album_links(&config, ""),
+        home: Some("../".to_owned()),
+        thumbnail: config.thumbnail_query(),
+    })
+}
+
 #[get("/about")]
-async fn about(cache: web::Data<Arc<Mutex<Cache>>>) -> impl Responder {
+async fn about(cache: web::Data<Cache>) -> impl Responder {
     use rand::prelude::*;
     let mut rng = rand::rng();
 
-    let cache_lock = cache.lock().await;
-    let len = cache_lock.len();
-    let images = cache_lock.get_images("images").await;
+    let len = cache.len();
+    let images = cache.get_images("images", &[], &[]).await;
     if let Some(random_image) = images.choose(&mut rng) {
         let page = AboutPage {
             image_count: len,
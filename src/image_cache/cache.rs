@@ -1,21 +1,136 @@
 use crate::cache::CacheTrait;
 use crate::config::AppConfig;
-use crate::endpoints::api::schema::ImageJson;
-use crate::image_cache::image::Image;
+use crate::endpoints::api::schema::{CacheStats, ImageJson, WarmupProgress};
+use crate::image_cache::captions::{Caption, CaptionStore, Sidecar};
+use crate::image_cache::daily::{self, DailyPicker};
+use crate::image_cache::derivatives::{DerivativeCache, DerivativeStore};
+use crate::image_cache::encoder::EncoderPool;
+use crate::image_cache::hidden::HiddenImages;
+use crate::image_cache::image::{
+    DateSource, Derivative, Fit, Image, MetaField, OutputFormat, Resize, UnsupportedImage,
+};
+use crate::image_cache::index::Index;
+use crate::image_cache::listing::{Cursor, Listing};
+use crate::image_cache::privacy::Stripping;
+use crate::image_cache::storage;
+use crate::image_cache::tags;
 
+use actix_web::web::{self, Bytes};
 use anyhow::anyhow;
-use chrono::{DateTime, Utc};
-use log::{debug, error, info, trace};
-use std::{collections::HashMap, path::PathBuf};
-use uuid::Uuid;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use chrono_tz::Tz;
+use log::{debug, error, info, trace, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use walkdir::WalkDir;
 
+/// Name stripped originals are stored under in the derivative directory
+const STRIPPED_VARIANT: &str = "stripped";
+
+/// Returned when the upload directory could not be created
+#[derive(Debug)]
+pub struct UploadsDisabled;
+
+impl fmt::Display for UploadsDisabled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uploads are disabled")
+    }
+}
+
+impl std::error::Error for UploadsDisabled {}
+
+/// Image metadata lives behind a read-write lock that is never held across an await,
+/// derivatives are encoded outside of it. Locks that are held together are always taken
+/// in the order `cache`, `hidden`, `captions`, `daily`, `index`.
 pub struct Cache {
     directories: Vec<PathBuf>,
-    cache: HashMap<String, Image>,
-    max_cache_age_ms: i64,
-    newest_image: Option<String>,
-    newest_image_time: DateTime<Utc>,
+    upload_dir: Option<PathBuf>,
+    trash_dir: PathBuf,
+    cache: RwLock<HashMap<String, Image>>,
+    index: Mutex<Index>,
+    date_sources: Vec<DateSource>,
+    derivatives: DerivativeStore,
+    /// Shared by every collection, so the memory budget holds for the whole server
+    memory: Arc<DerivativeCache>,
+    /// Shared by every collection, so the encode limits hold for the whole server
+    encoder: Arc<EncoderPool>,
+    /// How long an unused derivative stays in memory
+    max_idle: TimeDelta,
+    daily: Mutex<DailyPicker>,
+    hidden: Mutex<HiddenImages>,
+    captions: Mutex<CaptionStore>,
+    timezone: Tz,
+    warmup: Warmup,
+}
+
+/// What is left of an image after one of its files is forgotten
+enum Forgotten {
+    /// The image was removed along with its last file
+    Image(Box<Image>),
+    /// Another file is left, `served` if it took over as the one being served
+    Copy { served: bool },
+}
+
+/// Queue of images whose derivatives should be encoded ahead of time
+struct Warmup {
+    sender: UnboundedSender<String>,
+    receiver: Mutex<Option<UnboundedReceiver<String>>>,
+    derivatives: Vec<Derivative>,
+    count: usize,
+    queued: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Warmup {
+    fn new(config: &AppConfig) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        // Sizes that are not allowed could never be requested
+        let (sizes, disallowed): (Vec<u32>, Vec<u32>) = config
+            .prewarm_sizes
+            .iter()
+            .partition(|size| config.allowed_sizes.contains(size));
+        if !disallowed.is_empty() {
+            warn!(
+                "Not pre-warming sizes {:?}, they are not in allowed_sizes",
+                disallowed
+            );
+        }
+
+        // Compressed originals and the thumbnail widths the gallery asks for
+        let resizes = std::iter::once(None).chain(sizes.into_iter().map(|width| {
+            Some(Resize {
+                width: Some(width),
+                height: None,
+                fit: Fit::Contain,
+            })
+        }));
+        let derivatives = resizes
+            .flat_map(|resize| {
+                [OutputFormat::Avif, OutputFormat::WebP].map(|format| Derivative { format, resize })
+            })
+            .collect();
+
+        Self {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            derivatives,
+            count: config.prewarm_count,
+            queued: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
 }
 
 // todo: add custom error type
@@ -24,10 +139,19 @@ impl CacheTrait for Cache {
     type DataSource = PathBuf;
     type Data = Image;
     type Key = String;
+    type Variant = Derivative;
+
+    async fn insert_data(&self, img: &PathBuf) -> Result<String, anyhow::Error> {
+        if Sidecar::is_sidecar(img) {
+            return self.reload_sidecar(&Sidecar::image_path(img));
+        }
+        if storage::is_temporary(img) {
+            return Err(anyhow!("{:#?} is still being written", img));
+        }
 
-    async fn insert_data(&mut self, img: &PathBuf) -> Result<String, anyhow::Error> {
         let image_path = img.canonicalize()?;
-        if !image_path.metadata()?.is_file() {
+        let metadata = image_path.metadata()?;
+        if !metadata.is_file() {
             return Err(anyhow!("Passed a directory."));
         }
 
@@ -39,142 +163,404 @@ impl CacheTrait for Cache {
             return Err(anyhow!("Image is outside of scope"));
         }
 
-        let image: Image = {
-            let img_str = image_path
-                .to_str()
-                .ok_or(anyhow!("Image path is not valid."))?;
-            img_str.parse()?
-        };
-
-        let id = loop {
-            let id = Uuid::new_v4().to_string();
-            if !self.cache.contains_key(&id) {
-                break id;
+        // Only reprocess files that are new or have changed since they were indexed
+        let indexed = self
+            .lock_index()
+            .get(&image_path)
+            .filter(|entry| entry.is_fresh(&metadata))
+            .cloned();
+        let image: Image = match indexed {
+            Some(entry) => Image::try_from(&entry)?,
+            None => {
+                let img_str = image_path
+                    .to_str()
+                    .ok_or(anyhow!("Image path is not valid."))?;
+                let image = Image::load(img_str, &self.date_sources, &self.timezone)?;
+                self.lock_index().insert(image.to_index_entry());
+                image
             }
         };
-        debug!("Added to cache: {} => {:#?}", &id, &image_path);
 
-        self.cache.insert(id.to_owned(), image.clone());
-        Ok(id)
+        Ok(self.add_image(image))
     }
-    async fn remove_data(&mut self, image_path: &PathBuf) -> Option<Image> {
-        let image_id = self
-            .cache
+    async fn remove_data(&self, image_path: &PathBuf) -> Option<Image> {
+        if Sidecar::is_sidecar(image_path) {
+            let _ = self.reload_sidecar(&Sidecar::image_path(image_path));
+            return None;
+        }
+
+        self.lock_index().remove(image_path);
+
+        let mut cache = self.write_cache();
+        let image_id = cache
             .iter()
-            .find(|(_, img)| img.path == *image_path)
+            .find(|(_, img)| img.has_path(image_path))
             .map(|(key, _)| key.to_owned())?;
 
-        let image = self.cache.remove(&image_id);
-        if let Some(img) = &image {
-            debug!("Removed from cache: {} => {:#?}", image_id, img.path);
+        match self.forget_path(&mut cache, &image_id, image_path)? {
+            Forgotten::Image(image) => Some(*image),
+            Forgotten::Copy { served } => {
+                drop(cache);
+                if served {
+                    self.refresh_description(&image_id);
+                }
+                None
+            }
         }
+    }
+    async fn get_data(&self, key: &String) -> Result<Image, anyhow::Error> {
+        self.get_image(key).ok_or_else(|| anyhow!("no image found"))
+    }
+    async fn get_data_bytes(
+        &self,
+        key: &String,
+        derivative: &Derivative,
+    ) -> Result<(String, Bytes), anyhow::Error> {
+        let image = self.get_data(key).await?;
+
+        let data = self
+            .memory
+            .get_or_try_init(&image.hash, derivative, || async {
+                trace!("Resolving derivative {}", derivative.variant());
+                self.resolve_derivative(&image, derivative).await
+            })
+            .await?;
 
-        image
+        Ok((derivative.format.content_type().to_string(), data))
     }
-    async fn get_data(&mut self, key: &String) -> Result<Image, anyhow::Error> {
-        if let Some(cached_image) = self.cache.get_mut(key) {
-            trace!("Image present in cache, age {}", cached_image.cache_age());
 
-            // if it's not old enough AND the data is actually present
-            if cached_image.cache_age() < self.max_cache_age_ms && !cached_image.is_empty() {
-                trace!(
-                    "Cache has not expired and data is present, age: {}, size: {} bytes‚",
-                    &cached_image.cache_age(),
-                    cached_image.data.len()
-                );
-                return Ok(cached_image.clone());
-            }
+    /// How many images are shown, hidden ones are left out
+    fn len(&self) -> usize {
+        let cache = self.read_cache();
+        let hidden = self.lock_hidden();
+        cache.keys().filter(|key| !hidden.contains(key)).count()
+    }
 
-            trace!("Fetching image from disk");
-            cached_image.resolve()?;
-            return Ok(cached_image.clone());
+    fn clean_cache(&self) {
+        // Cache
+        let cleared_images = self.memory.clean(self.max_idle);
+
+        if cleared_images > 0 {
+            debug!("Cleaned {} images from cache.", cleared_images)
         }
 
-        Err(anyhow!("no image found"))
+        let stats = self.memory.stats();
+        debug!(
+            "Derivative cache: {} hits, {} misses, {} evictions, {}/{} bytes",
+            stats.hits, stats.misses, stats.evictions, stats.used_bytes, stats.budget_bytes
+        );
     }
-    async fn get_data_bytes(
-        &mut self,
-        key: &String,
-        compress: bool,
-    ) -> Result<(String, Vec<u8>), anyhow::Error> {
-        if let Some(cached_image) = self.cache.get_mut(key) {
-            trace!("Image present in cache, age {}", cached_image.cache_age());
-
-            if compress {
-                if !cached_image.compressed_is_empty() {
-                    return Ok((
-                        "image/webp".to_string(),
-                        cached_image.compressed_data.clone(),
-                    ));
-                }
+    fn directories(&self) -> Vec<PathBuf> {
+        self.directories.clone()
+    }
+
+    fn persist(&self) {
+        if let Err(e) = self.lock_index().save() {
+            error!("Error saving image index: {}", e);
+        }
+    }
+
+    fn queue_warmup(&self, key: String) {
+        if self.warmup.count == 0 {
+            return;
+        }
+        if self.warmup.sender.send(key).is_ok() {
+            self.warmup.queued.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn warmup_queue(&self) -> Option<UnboundedReceiver<String>> {
+        self.warmup
+            .receiver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    async fn warm_data(&self, key: &String) -> Result<(), anyhow::Error> {
+        let result = self.warm_derivatives(key).await;
+        match &result {
+            Ok(()) => self.warmup.completed.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.warmup.failed.fetch_add(1, Ordering::Relaxed),
+        };
+
+        let progress = self.warmup_progress();
+        if progress.pending == 0 || (progress.completed + progress.failed).is_multiple_of(10) {
+            info!(
+                "Pre-warmed {}/{} images ({} failed)",
+                progress.completed, progress.queued, progress.failed
+            );
+        }
+
+        result
+    }
+}
+
+impl Cache {
+    fn read_cache(&self) -> RwLockReadGuard<'_, HashMap<String, Image>> {
+        self.cache.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_cache(&self) -> RwLockWriteGuard<'_, HashMap<String, Image>> {
+        self.cache.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_index(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_daily(&self) -> MutexGuard<'_, DailyPicker> {
+        self.daily.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_hidden(&self) -> MutexGuard<'_, HiddenImages> {
+        self.hidden.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_captions(&self) -> MutexGuard<'_, CaptionStore> {
+        self.captions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fills in the caption and tags of an image. Captions from the admin API win over
+    /// sidecar files, which win over embedded XMP, tags from every source are merged.
+    /// The sidecar is read by the caller, so no disk access happens under the cache lock.
+    fn describe(&self, key: &str, image: &mut Image, sidecar: Sidecar) {
+        image.caption = self
+            .lock_captions()
+            .get(key)
+            .or(sidecar.caption)
+            .or(image.embedded_caption.clone());
+        image.tags = tags::normalize(
+            image
+                .embedded_tags
+                .iter()
+                .chain(&sidecar.tags)
+                .chain(&self.directory_tags(&image.path)),
+        );
+    }
+
+    /// Adds a loaded image to the cache, or records it as another copy of an image with the
+    /// same contents. Returns its ID.
+    fn add_image(&self, mut image: Image) -> String {
+        // IDs are derived from the file contents so they survive restarts
+        let id = image.hash.clone();
+        let image_path = image.path.clone();
+
+        self.describe(&id, &mut image, Sidecar::read(&image_path));
+        let mut cache = self.write_cache();
+
+        // The file at this path may have been overwritten with new contents
+        let stale_id = cache
+            .iter()
+            .find(|(key, img)| img.has_path(&image_path) && **key != id)
+            .map(|(key, _)| key.to_owned());
+        let mut moved = None;
+        if let Some(stale_id) = stale_id {
+            debug!(
+                "Replacing stale cache entry: {} => {:#?}",
+                stale_id, &image_path
+            );
+            if let Some(Forgotten::Copy { served: true }) =
+                self.forget_path(&mut cache, &stale_id, &image_path)
+            {
+                moved = Some(stale_id);
             }
+        }
 
-            // if it's not old enough AND the data is actually present
-            if cached_image.cache_age() < self.max_cache_age_ms && !cached_image.is_empty() {
-                trace!(
-                    "Cache has not expired and data is present, age: {}, size: {} bytes‚",
-                    &cached_image.cache_age(),
-                    cached_image.data.len()
+        match cache.get_mut(&id) {
+            Some(existing) if !existing.has_path(&image_path) => {
+                debug!(
+                    "Duplicate image {:#?} already cached as {:#?}",
+                    &image_path, existing.path
                 );
-                return Ok((cached_image.content_type(), cached_image.data.clone()));
+                existing.copies.push(image_path);
+            }
+            Some(_) => {}
+            None => {
+                debug!("Added to cache: {} => {:#?}", &id, &image_path);
+                cache.insert(id.to_owned(), image);
             }
-
-            trace!("Fetching image from disk");
-            cached_image.resolve()?;
-            return Ok((cached_image.content_type(), cached_image.data.clone()));
         }
 
-        Err(anyhow!("no image found"))
+        drop(cache);
+        if let Some(moved) = moved {
+            self.refresh_description(&moved);
+        }
+        id
     }
 
-    fn len(&self) -> usize {
-        self.cache.len()
+    /// Drops `path` from the image with ID `key`, the image itself only goes with its last file.
+    /// Directory tags and sidecars follow the file that is served, so callers refresh the
+    /// description once the lock is released if a copy takes over.
+    fn forget_path(
+        &self,
+        cache: &mut HashMap<String, Image>,
+        key: &str,
+        path: &Path,
+    ) -> Option<Forgotten> {
+        let image = cache.get_mut(key)?;
+        let served = image.path.clone();
+        if image.remove_path(path) {
+            if image.path != served {
+                debug!("Serving copy {:#?} for {}", image.path, key);
+            }
+            return Some(Forgotten::Copy {
+                served: image.path != served,
+            });
+        }
+
+        let image = cache.remove(key)?;
+        debug!("Removed from cache: {} => {:#?}", key, image.path);
+        self.memory.remove(key);
+        Some(Forgotten::Image(Box::new(image)))
     }
 
-    fn clean_cache(&mut self) {
-        let mut cleared_images = 0;
+    /// Reads the caption and tags of an image again, the sidecar is read without holding a lock
+    fn refresh_description(&self, key: &str) {
+        let Some(mut image) = self.read_cache().get(key).cloned() else {
+            return;
+        };
 
-        // Cache
-        for (_, image) in self.cache.iter_mut() {
-            if image.cache_age() > self.max_cache_age_ms && !image.is_empty() {
-                image.clear();
-                cleared_images += 1;
-            }
+        let sidecar = Sidecar::read(&image.path);
+        self.describe(key, &mut image, sidecar);
+        if let Some(img) = self.write_cache().get_mut(key)
+            && img.path == image.path
+        {
+            img.caption = image.caption;
+            img.tags = image.tags;
         }
+    }
+
+    /// Subdirectory names below the most specific configured directory holding `path`
+    fn directory_tags(&self, path: &Path) -> Vec<String> {
+        self.directories
+            .iter()
+            .filter(|dir| path.starts_with(dir))
+            .max_by_key(|dir| dir.components().count())
+            .map(|dir| tags::from_directory(dir, path))
+            .unwrap_or_default()
+    }
+
+    /// Picks up changes to the sidecar of the image at `image_path`
+    fn reload_sidecar(&self, image_path: &Path) -> Result<String, anyhow::Error> {
+        let image_path = image_path.canonicalize()?;
+        let key = self
+            .read_cache()
+            .iter()
+            .find(|(_, img)| img.path == image_path)
+            .map(|(key, _)| key.to_owned())
+            .ok_or_else(|| anyhow!("No image for sidecar {:#?}", image_path))?;
+
+        self.refresh_description(&key);
+        debug!("Reloaded sidecar of {}", key);
+        Ok(key)
+    }
 
-        // Daily image
-        let newest_image_age = (Utc::now() - self.newest_image_time).num_milliseconds();
-        if newest_image_age > self.max_cache_age_ms {
-            self.newest_image = None
+    /// Loads a derivative from disk, or encodes and stores it
+    async fn resolve_derivative(
+        &self,
+        image: &Image,
+        derivative: &Derivative,
+    ) -> Result<Bytes, anyhow::Error> {
+        let variant = derivative.variant();
+        if let Some(data) = self.derivatives.get(&image.hash, &variant).await {
+            return Ok(data);
         }
 
-        if cleared_images > 0 {
-            debug!("Cleaned {} images from cache.", cleared_images)
+        let (source, derivative) = (image.clone(), *derivative);
+        let data = Bytes::from(self.encoder.run(move || source.encode(&derivative)).await?);
+        self.derivatives
+            .put(&image.hash, &variant, data.clone())
+            .await;
+        Ok(data)
+    }
+
+    /// Encodes every pre-warm derivative of an image that is not on disk yet
+    async fn warm_derivatives(&self, key: &String) -> Result<(), anyhow::Error> {
+        let image = self.get_data(key).await?;
+
+        for derivative in &self.warmup.derivatives {
+            let variant = derivative.variant();
+            if self
+                .derivatives
+                .locate(&image.hash, &variant)
+                .await
+                .is_some()
+            {
+                continue;
+            }
+
+            trace!("Pre-warming {} {}", image.hash, variant);
+            let (source, derivative) = (image.clone(), *derivative);
+            let data = self
+                .encoder
+                .run_background(move || source.encode(&derivative))
+                .await?;
+            self.derivatives
+                .put(&image.hash, &variant, Bytes::from(data))
+                .await;
         }
+
+        Ok(())
     }
-    fn directories(&self) -> Vec<PathBuf> {
-        self.directories.clone()
+
+    pub fn warmup_progress(&self) -> WarmupProgress {
+        let queued = self.warmup.queued.load(Ordering::Relaxed);
+        let completed = self.warmup.completed.load(Ordering::Relaxed);
+        let failed = self.warmup.failed.load(Ordering::Relaxed);
+
+        WarmupProgress {
+            queued,
+            completed,
+            failed,
+            pending: queued.saturating_sub(completed + failed),
+        }
     }
-}
 
-impl Cache {
-    /// Fills up the cache without resolving data.
+    /// Fills up the cache with image metadata, originals are always streamed from disk.
     pub async fn init(&mut self, config: &AppConfig) {
-        let directories = &config.directories;
         self.directories = config
-            .directories
+            .image_directories()
             .iter()
             .map(|d| PathBuf::from(d).canonicalize().unwrap())
             .collect();
 
+        // The upload directory is watched like the others unless it already lives inside one
+        if let Some(upload_dir) = &config.upload_dir {
+            match std::fs::create_dir_all(upload_dir)
+                .and_then(|_| PathBuf::from(upload_dir).canonicalize())
+            {
+                Ok(upload_dir) => {
+                    if !self
+                        .directories
+                        .iter()
+                        .any(|dir| upload_dir.starts_with(dir))
+                    {
+                        self.directories.push(upload_dir.clone());
+                    }
+                    self.upload_dir = Some(upload_dir);
+                }
+                Err(e) => {
+                    error!(
+                        "Uploads are disabled, upload directory {} is unusable: {}",
+                        upload_dir, e
+                    );
+                }
+            }
+        } else {
+            self.upload_dir = self.directories.first().cloned();
+        }
+
         let mut files = Vec::new();
-        for dir in directories {
+        for dir in &self.directories {
             files.extend_from_slice(
                 &WalkDir::new(dir)
                     .into_iter()
                     .filter_map(Result::ok)
                     .filter(|e| e.file_type().is_file())
                     .map(|e| e.into_path())
+                    // Sidecars are read along with their images
+                    .filter(|path| !Sidecar::is_sidecar(path) && !storage::is_temporary(path))
                     .collect::<Vec<PathBuf>>(),
             )
         }
@@ -184,16 +570,51 @@ impl Cache {
                 error!("Error inserting image to cache: {}", e);
             }
         }
+
+        // Forget about files that disappeared while we were not running
+        let present: HashSet<PathBuf> =
+            files.iter().filter_map(|f| f.canonicalize().ok()).collect();
+        self.lock_index().retain(&present);
+        self.persist();
+
+        let hashes: HashSet<String> = self.read_cache().keys().cloned().collect();
+        self.derivatives.prune(&hashes);
+
+        // Encode derivatives for the newest images before anyone asks for them
+        let mut newest: Vec<(String, DateTime<Utc>)> = {
+            let cache = self.read_cache();
+            let hidden = self.lock_hidden();
+            cache
+                .iter()
+                .filter(|(key, _)| !hidden.contains(key))
+                .map(|(key, img)| (key.to_owned(), img.image_age))
+                .collect()
+        };
+        newest.sort_by_key(|(_key, age)| std::cmp::Reverse(*age));
+        for (key, _) in newest.into_iter().take(self.warmup.count) {
+            self.queue_warmup(key);
+        }
+
         info!(
             "Cache startup finalized. Added {} files to cache.",
             files.len()
         );
     }
 
-    pub async fn get_images(&self, prefix: &str) -> Vec<ImageJson> {
-        let mut images: Vec<(&str, &Image)> = self
-            .cache
+    /// Every visible image, newest first. Only images with one of `tags` are returned
+    /// unless it is empty.
+    pub async fn get_images(
+        &self,
+        prefix: &str,
+        fields: &[MetaField],
+        tags: &[String],
+    ) -> Vec<ImageJson> {
+        let cache = self.read_cache();
+        let hidden = self.lock_hidden();
+        let mut images: Vec<(&str, &Image)> = cache
             .iter()
+            .filter(|(key, _)| !hidden.contains(key))
+            .filter(|(_, img)| tags.is_empty() || img.has_any_tag(tags))
             .map(|(key, data)| (key.as_str(), data))
             .collect();
 
@@ -204,45 +625,465 @@ impl Cache {
         // Return transformed images :)
         images
             .into_iter()
-            .map(|(key, img)| ImageJson::from((format!("{}/{}", prefix, key), img.to_owned())))
+            .map(|(key, img)| ImageJson::new(format!("{}/{}", prefix, key), img, fields))
             .collect()
     }
 
-    async fn get_newest_image_id(&mut self) -> Option<String> {
-        if let Some(id) = &self.newest_image {
-            return Some(id.to_owned());
+    /// Writes an uploaded image to the upload directory and adds it to the cache.
+    /// Returns the ID and whether the image was new.
+    pub async fn insert_upload(&self, data: Vec<u8>) -> Result<(String, bool), anyhow::Error> {
+        let upload_dir = self.upload_dir.as_ref().ok_or(UploadsDisabled)?;
+        let extension = Image::extension_for(&data).ok_or(UnsupportedImage)?;
+
+        let id = format!("{:x}", Sha256::digest(&data));
+        if self.read_cache().contains_key(&id) {
+            return Ok((id, false));
+        }
+
+        let path = upload_dir.join(format!("{}.{}", id, extension));
+        let (date_sources, timezone, hash) = (self.date_sources.clone(), self.timezone, id);
+        // Renamed into place once complete, so the directory watcher never reads a partial file
+        let image = web::block(move || {
+            storage::write_atomic(&path, &data)?;
+            info!("Stored upload {:#?}", &path);
+            let path = path.to_str().ok_or(anyhow!("Upload path is not valid."))?;
+            Image::load_with_hash(path, &date_sources, &timezone, hash)
+        })
+        .await
+        .map_err(|e| anyhow!("Storing upload failed: {}", e))??;
+
+        self.lock_index().insert(image.to_index_entry());
+        let id = self.add_image(image);
+        self.persist();
+        self.queue_warmup(id.clone());
+        Ok((id, true))
+    }
+
+    /// Path and size of the original without its privacy sensitive metadata. Stripped copies
+    /// are written next to the derivatives on first use, so later requests stream from disk.
+    pub async fn stripped_original(&self, image: &Image) -> Result<(PathBuf, u64), anyhow::Error> {
+        match image.stripping() {
+            Stripping::Unneeded => return Ok((image.path.clone(), image.size)),
+            Stripping::Reencode => {
+                return Err(anyhow!("{:#?} has to be re-encoded", image.path));
+            }
+            Stripping::Rewrite => {}
+        }
+
+        if let Some(stripped) = self.derivatives.locate(&image.hash, STRIPPED_VARIANT).await {
+            return Ok(stripped);
+        }
+
+        let source = image.clone();
+        let data = web::block(move || source.read_stripped())
+            .await
+            .map_err(|e| anyhow!("Stripping metadata failed: {}", e))??;
+        self.derivatives
+            .put(&image.hash, STRIPPED_VARIANT, Bytes::from(data))
+            .await;
+
+        self.derivatives
+            .locate(&image.hash, STRIPPED_VARIANT)
+            .await
+            .ok_or_else(|| anyhow!("Unable to store stripped original of {}", image.hash))
+    }
+
+    /// One page of images, ordered and filtered as `listing` asks
+    pub fn get_image_page(&self, listing: &Listing) -> (Vec<(String, Image)>, Option<Cursor>) {
+        let cache = self.read_cache();
+        let hidden = self.lock_hidden();
+        listing.page(cache.iter().filter(|(key, _)| !hidden.contains(key)))
+    }
+
+    /// Hides an image everywhere without touching its file, returns false for unknown images
+    pub fn hide_image(&self, key: &String) -> bool {
+        if !self.read_cache().contains_key(key) {
+            return false;
+        }
+
+        if self.lock_hidden().hide(key) {
+            info!("Hid image {}", key);
+        }
+        true
+    }
+
+    /// Shows a hidden image again, returns false if it was not hidden
+    pub fn unhide_image(&self, key: &String) -> bool {
+        let restored = self.lock_hidden().unhide(key);
+        if restored {
+            info!("Restored image {}", key);
         }
-        self.newest_image = self
-            .cache
+        restored
+    }
+
+    /// Overrides the caption of an image, returns false for unknown images
+    pub fn set_caption(&self, key: &String, caption: Caption) -> bool {
+        let Some(mut image) = self.read_cache().get(key).cloned() else {
+            return false;
+        };
+
+        self.lock_captions().set(key, caption);
+        let sidecar = Sidecar::read(&image.path);
+        self.describe(key, &mut image, sidecar);
+        if let Some(img) = self.write_cache().get_mut(key) {
+            img.caption = image.caption;
+        }
+        true
+    }
+
+    pub fn hidden_images(&self) -> Vec<String> {
+        self.lock_hidden().ids()
+    }
+
+    /// Moves every copy of an image, hidden or not, to the trash directory and forgets about it.
+    /// Returns where the files ended up, or None for unknown images.
+    pub async fn delete_image(&self, key: &String) -> Result<Option<Vec<PathBuf>>, anyhow::Error> {
+        let Some(image) = self.read_cache().get(key).cloned() else {
+            return Ok(None);
+        };
+
+        let mut targets = Vec::new();
+        for path in image.paths() {
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("Image has no file name"))?;
+            let mut target = self.trash_dir.join(file_name);
+            let mut attempt = 0;
+            while target.exists() || targets.contains(&target) {
+                attempt += 1;
+                target = self.trash_dir.join(format!(
+                    "{}-{}-{}",
+                    key,
+                    attempt,
+                    file_name.to_string_lossy()
+                ));
+            }
+
+            let (source, destination) = (path.clone(), target.clone());
+            web::block(move || move_file(&source, &destination))
+                .await
+                .map_err(|e| anyhow!("Moving image to trash failed: {}", e))??;
+            info!("Moved {:#?} to {:#?}", path, target);
+            self.remove_data(path).await;
+
+            // The sidecar goes along, so a restored image keeps its caption and tags
+            let sidecar = Sidecar::path(path);
+            if sidecar.exists() {
+                let destination = Sidecar::path(&target);
+                web::block(move || move_file(&sidecar, &destination))
+                    .await
+                    .map_err(|e| anyhow!("Moving sidecar to trash failed: {}", e))??;
+            }
+            targets.push(target);
+        }
+
+        self.persist();
+        self.lock_hidden().unhide(key);
+        self.lock_captions().remove(key);
+        // Encoded and stripped copies would otherwise outlive the original until the next prune
+        self.memory.remove(key);
+        self.derivatives.remove(key).await;
+        Ok(Some(targets))
+    }
+
+    /// How many visible images have one of `tags`, and the ID of the newest of them
+    pub fn tag_summary(&self, tags: &[String]) -> (usize, Option<String>) {
+        let cache = self.read_cache();
+        let hidden = self.lock_hidden();
+        let tagged: Vec<(&String, &Image)> = cache
             .iter()
-            .max_by_key(|img_tuple| img_tuple.1.image_age)
-            .map(|(key, _)| key.to_owned());
+            .filter(|(key, img)| !hidden.contains(key) && img.has_any_tag(tags))
+            .collect();
+
+        let newest = tagged
+            .iter()
+            .max_by_key(|(_, img)| img.image_age)
+            .map(|(key, _)| key.to_string());
+        (tagged.len(), newest)
+    }
 
-        self.newest_image.clone()
+    pub fn stats(&self) -> CacheStats {
+        self.memory.stats()
     }
 
-    pub async fn get_newest_image(&mut self) -> Option<Image> {
-        if let Some(id) = self.get_newest_image_id().await {
-            return self.get_data(&id).await.ok();
+    /// Looks up an image that is not hidden
+    pub fn get_image(&self, key: &String) -> Option<Image> {
+        let cache = self.read_cache();
+        if self.lock_hidden().contains(key) {
+            return None;
         }
 
-        None
+        cache.get(key).cloned()
+    }
+
+    /// The current date in the configured timezone
+    pub fn today(&self) -> NaiveDate {
+        daily::local_date(&self.timezone, Utc::now())
+    }
+
+    /// The first instant of `date` in the configured timezone
+    pub fn start_of_day(&self, date: NaiveDate) -> Option<DateTime<Utc>> {
+        daily::start_of_day(&self.timezone, date)
+    }
+
+    /// When the daily image changes next
+    pub fn next_daily_rollover(&self) -> DateTime<Utc> {
+        daily::next_rollover(&self.timezone, Utc::now())
+    }
+
+    pub async fn get_daily_image(&self) -> Option<Image> {
+        let today = self.today();
+        // Once today's image is picked it is looked up directly. The pick is read on its own,
+        // a guard in the condition would hold `daily` while `get_image` takes `cache`.
+        let picked = self.lock_daily().picked_on(today);
+        if let Some(id) = picked
+            && let Some(image) = self.get_image(&id)
+        {
+            return Some(image);
+        }
+
+        let cache = self.read_cache();
+        let hidden = self.lock_hidden();
+        let images: HashMap<&str, &Image> = cache
+            .iter()
+            .filter(|(key, _)| !hidden.contains(key))
+            .map(|(key, img)| (key.as_str(), img))
+            .collect();
+        let id = self.lock_daily().pick(today, &images)?;
+
+        images.get(id.as_str()).map(|img| (*img).to_owned())
+    }
+
+    /// The image that was shown on `date`, today's is picked if nobody asked for it yet
+    pub async fn get_daily_image_on(&self, date: NaiveDate) -> Option<Image> {
+        if date == self.today() {
+            return self.get_daily_image().await;
+        }
+
+        let id = self.lock_daily().picked_on(date)?;
+        self.get_image(&id)
+    }
+
+    /// Past picks between `from` and `to` whose images still exist
+    pub async fn get_daily_history(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Vec<(NaiveDate, String)> {
+        // Make sure today's pick is part of the log
+        if (from..=to).contains(&self.today()) {
+            self.get_daily_image().await;
+        }
+
+        let history = self.lock_daily().history(from, to);
+        let cache = self.read_cache();
+        let hidden = self.lock_hidden();
+
+        history
+            .into_iter()
+            .filter(|(_date, id)| cache.contains_key(id) && !hidden.contains(id))
+            .collect()
     }
 }
 
-impl From<i64> for Cache {
-    fn from(cache_max_age: i64) -> Self {
+impl Cache {
+    /// A cache that keeps derivatives in `memory` and encodes them with `encoder`,
+    /// which may be shared with other caches
+    pub fn new(
+        config: &AppConfig,
+        memory: Arc<DerivativeCache>,
+        encoder: Arc<EncoderPool>,
+    ) -> Self {
         info!(
-            "Initializing a cache with a maximum TTL of {}ms",
-            cache_max_age
+            "Initializing a cache that keeps unused derivatives for {}s",
+            config.memory_idle_secs
         );
 
         Self {
             directories: Vec::new(),
-            cache: HashMap::new(),
-            max_cache_age_ms: cache_max_age,
-            newest_image: None,
-            newest_image_time: Utc::now(),
+            upload_dir: None,
+            trash_dir: PathBuf::from(&config.trash_dir),
+            cache: RwLock::new(HashMap::new()),
+            index: Mutex::new(Index::load(
+                &config.index_path,
+                &config.date_sources,
+                config.timezone,
+            )),
+            date_sources: config.date_sources.clone(),
+            derivatives: DerivativeStore::new(&config.derivative_dir),
+            memory,
+            encoder,
+            max_idle: TimeDelta::seconds(config.memory_idle_secs.into()),
+            daily: Mutex::new(DailyPicker::new(
+                config.daily_policy,
+                &config.daily_schedule_path,
+                &config.daily_state_path,
+            )),
+            hidden: Mutex::new(HiddenImages::load(&config.hidden_path)),
+            captions: Mutex::new(CaptionStore::load(&config.captions_path)),
+            timezone: config.timezone,
+            warmup: Warmup::new(config),
+        }
+    }
+}
+
+/// Renames a file, copying it when the trash lives on another file system
+fn move_file(source: &Path, destination: &Path) -> Result<(), std::io::Error> {
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    if std::fs::rename(source, destination).is_err() {
+        std::fs::copy(source, destination)?;
+        std::fs::remove_file(source)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat, RgbImage};
+
+    /// An empty directory for the images and state of one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jorge-cache-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn cache(config: &AppConfig) -> Cache {
+        let mut cache = Cache::new(
+            config,
+            Arc::new(DerivativeCache::new(1024)),
+            Arc::new(EncoderPool::new(1, 0)),
+        );
+        cache.init(config).await;
+        cache
+    }
+
+    fn png() -> Vec<u8> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(4, 4))
+            .write_to(&mut data, ImageFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn only_prewarms_allowed_sizes() {
+        let dir = test_dir("prewarm");
+        let config = AppConfig {
+            prewarm_sizes: vec![400, 500, 800],
+            allowed_sizes: vec![200, 400, 800],
+            ..AppConfig::test_in(&dir)
+        };
+
+        let widths: Vec<Option<u32>> = Warmup::new(&config)
+            .derivatives
+            .iter()
+            .map(|derivative| derivative.resize.and_then(|resize| resize.width))
+            .collect();
+        assert_eq!(
+            widths,
+            [None, None, Some(400), Some(400), Some(800), Some(800)]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn stores_uploads_in_the_upload_directory() {
+        let dir = test_dir("upload");
+        let config = AppConfig {
+            upload_dir: Some(dir.join("uploads").to_string_lossy().into_owned()),
+            ..AppConfig::test_in(&dir)
+        };
+        let cache = cache(&config).await;
+
+        let (id, created) = cache.insert_upload(png()).await.unwrap();
+        assert!(created);
+        assert_eq!(files(&dir.join("uploads")), [format!("{}.png", id)]);
+        assert!(cache.get_image(&id).is_some());
+
+        // The same bytes again are the same image
+        assert_eq!(
+            cache.insert_upload(png()).await.unwrap(),
+            (id.clone(), false)
+        );
+        assert_eq!(files(&dir.join("uploads")), [format!("{}.png", id)]);
+        assert_eq!(cache.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn descriptions_follow_the_served_copy() {
+        let dir = test_dir("copies");
+        let config = AppConfig::test_in(&dir);
+        for name in ["sofa", "window"] {
+            std::fs::create_dir_all(dir.join("images").join(name)).unwrap();
+            std::fs::write(dir.join("images").join(name).join("jorge.png"), png()).unwrap();
+            std::fs::write(
+                dir.join("images").join(name).join("jorge.png.toml"),
+                format!("title = \"On the {}\"", name),
+            )
+            .unwrap();
         }
+        let cache = cache(&config).await;
+
+        let id = cache.read_cache().keys().next().unwrap().to_owned();
+        let served = cache.get_image(&id).unwrap();
+        let other = if served.tags == ["sofa"] {
+            "window"
+        } else {
+            "sofa"
+        };
+        cache.remove_data(&served.path).await;
+
+        let image = cache.get_image(&id).unwrap();
+        assert_eq!(image.tags, [other]);
+        assert_eq!(image.caption.title, Some(format!("On the {}", other)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rejects_uploads_that_are_not_images() {
+        let dir = test_dir("reject");
+        let config = AppConfig::test_in(&dir);
+        let cache = cache(&config).await;
+
+        for data in [
+            b"just some text, not an image".to_vec(),
+            png()[..8].to_vec(),
+        ] {
+            let error = cache.insert_upload(data).await.unwrap_err();
+            assert!(error.is::<UnsupportedImage>());
+        }
+        assert!(files(&dir.join("images")).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn refuses_uploads_without_an_upload_directory() {
+        let dir = test_dir("disabled");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let config = AppConfig {
+            upload_dir: Some(dir.join("file/uploads").to_string_lossy().into_owned()),
+            ..AppConfig::test_in(&dir)
+        };
+        let cache = cache(&config).await;
+
+        let error = cache.insert_upload(png()).await.unwrap_err();
+        assert!(error.is::<UploadsDisabled>());
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,308 @@
+use crate::image_cache::segments::find;
+use crate::image_cache::storage::{read_json, write_json_atomic};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+const SIDECAR_EXTENSION: &str = "toml";
+
+/// Human written text about an image
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Caption {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+}
+
+impl Caption {
+    /// Fills the fields that are missing from `fallback`
+    pub fn or(self, fallback: Caption) -> Caption {
+        Caption {
+            title: self.title.or(fallback.title),
+            caption: self.caption.or(fallback.caption),
+            alt: self.alt.or(fallback.alt),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.caption.is_none() && self.alt.is_none()
+    }
+
+    /// Drops blank fields, so clearing a field in a sidecar falls back to the embedded text
+    fn trimmed(self) -> Caption {
+        let trim = |text: Option<String>| {
+            text.map(|text| text.trim().to_owned())
+                .filter(|text| !text.is_empty())
+        };
+
+        Caption {
+            title: trim(self.title),
+            caption: trim(self.caption),
+            alt: trim(self.alt),
+        }
+    }
+
+    /// Title, description and alt text from the XMP packet embedded in an image
+    pub fn from_xmp(data: &[u8]) -> Caption {
+        let Some(xmp) = xmp_packet(data) else {
+            return Caption::default();
+        };
+
+        Caption {
+            title: xmp_text(xmp, "dc:title"),
+            caption: xmp_text(xmp, "dc:description"),
+            alt: xmp_text(xmp, "Iptc4xmpCore:AltTextAccessibility"),
+        }
+        .trimmed()
+    }
+}
+
+/// Contents of the sidecar file next to an image, `photo.jpg` is described by `photo.jpg.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Sidecar {
+    #[serde(flatten)]
+    pub caption: Caption,
+    pub tags: Vec<String>,
+}
+
+impl Sidecar {
+    /// Whether `path` is the sidecar of an image rather than an image
+    pub fn is_sidecar(path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|extension| extension == SIDECAR_EXTENSION)
+    }
+
+    /// The image a sidecar describes
+    pub fn image_path(path: &Path) -> PathBuf {
+        path.with_extension("")
+    }
+
+    /// Where the sidecar of the image at `image_path` is, e.g. `cat.jpg.toml` for `cat.jpg`
+    pub fn path(image_path: &Path) -> PathBuf {
+        let mut path = image_path.as_os_str().to_owned();
+        path.push(".");
+        path.push(SIDECAR_EXTENSION);
+        PathBuf::from(path)
+    }
+
+    /// Reads the sidecar of the image at `image_path`, if there is one
+    pub fn read(image_path: &Path) -> Sidecar {
+        let path = Self::path(image_path);
+
+        let Ok(data) = std::fs::read_to_string(&path) else {
+            return Sidecar::default();
+        };
+        match toml::from_str::<Sidecar>(&data) {
+            Ok(sidecar) => Sidecar {
+                caption: sidecar.caption.trimmed(),
+                tags: sidecar.tags,
+            },
+            Err(e) => {
+                warn!("Sidecar {:#?} is invalid: {}", path, e);
+                Sidecar::default()
+            }
+        }
+    }
+}
+
+/// Captions set through the admin API, they win over sidecars and embedded metadata
+pub struct CaptionStore {
+    path: PathBuf,
+    captions: BTreeMap<String, Caption>,
+}
+
+impl CaptionStore {
+    pub fn load(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        let captions = read_json(&path, "Captions");
+
+        Self { path, captions }
+    }
+
+    pub fn get(&self, id: &str) -> Caption {
+        self.captions.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Replaces the caption of an image, an empty one removes it
+    pub fn set(&mut self, id: &str, caption: Caption) {
+        let caption = caption.trimmed();
+        if caption.is_empty() {
+            self.captions.remove(id);
+        } else {
+            self.captions.insert(id.to_owned(), caption);
+        }
+        self.persist();
+    }
+
+    /// Forgets the caption of an image that was deleted
+    pub fn remove(&mut self, id: &str) {
+        if self.captions.remove(id).is_some() {
+            self.persist();
+        }
+    }
+
+    fn persist(&self) {
+        if let Err(e) = write_json_atomic(&self.path, &self.captions) {
+            error!("Error saving captions: {}", e);
+        }
+    }
+}
+
+/// The first XMP packet in `data`, wherever the container put it
+pub fn xmp_packet(data: &[u8]) -> Option<&str> {
+    let start = find(data, b"<x:xmpmeta")?;
+    let end = start + find(&data[start..], b"</x:xmpmeta>")?;
+    std::str::from_utf8(&data[start..end]).ok()
+}
+
+/// Text of a language alternative property, e.g.
+/// `<dc:title><rdf:Alt><rdf:li xml:lang="x-default">Jorge</rdf:li></rdf:Alt></dc:title>`
+fn xmp_text(xmp: &str, property: &str) -> Option<String> {
+    // Simple values may also be written as attributes
+    let attribute = format!("{}=\"", property);
+    if let Some(start) = xmp.find(&attribute) {
+        let value = &xmp[start + attribute.len()..];
+        return value.find('"').map(|end| unescape(&value[..end]));
+    }
+
+    let open = format!("<{}>", property);
+    let close = format!("</{}>", property);
+    let start = xmp.find(&open)? + open.len();
+    let element = &xmp[start..start + xmp[start..].find(&close)?];
+
+    let text = match element.find("<rdf:li") {
+        Some(item) => {
+            let item = &element[item..];
+            let start = item.find('>')? + 1;
+            item.get(start..item.find("</rdf:li>")?)?
+        }
+        None => element,
+    };
+    Some(unescape(text))
+}
+
+/// Every item of a list property, e.g.
+/// `<dc:subject><rdf:Bag><rdf:li>sleeping</rdf:li><rdf:li>sofa</rdf:li></rdf:Bag></dc:subject>`
+pub fn xmp_list(xmp: &str, property: &str) -> Vec<String> {
+    let open = format!("<{}>", property);
+    let close = format!("</{}>", property);
+    let Some(start) = xmp.find(&open).map(|start| start + open.len()) else {
+        return Vec::new();
+    };
+    let Some(end) = xmp[start..].find(&close) else {
+        return Vec::new();
+    };
+
+    xmp[start..start + end]
+        .split("<rdf:li")
+        .skip(1)
+        .filter_map(|item| {
+            let start = item.find('>')? + 1;
+            Some(unescape(item.get(start..item.find("</rdf:li>")?)?))
+        })
+        .collect()
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xmp(properties: &str) -> Vec<u8> {
+        let mut data = b"\xFF\xD8 image data ".to_vec();
+        data.extend_from_slice(
+            format!(
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF><rdf:Description {}</rdf:Description></rdf:RDF></x:xmpmeta>",
+                properties
+            )
+            .as_bytes(),
+        );
+        data
+    }
+
+    #[test]
+    fn reads_language_alternatives() {
+        let data = xmp(concat!(
+            ">",
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">Jorge</rdf:li></rdf:Alt></dc:title>",
+            "<dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\"> On the sofa </rdf:li>",
+            "<rdf:li xml:lang=\"fi\">Sohvalla</rdf:li></rdf:Alt></dc:description>",
+            "<Iptc4xmpCore:AltTextAccessibility><rdf:Alt><rdf:li xml:lang=\"x-default\">A grey cat",
+            "</rdf:li></rdf:Alt></Iptc4xmpCore:AltTextAccessibility>",
+        ));
+
+        assert_eq!(
+            Caption::from_xmp(&data),
+            Caption {
+                title: Some("Jorge".to_owned()),
+                caption: Some("On the sofa".to_owned()),
+                alt: Some("A grey cat".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn reads_attributes_and_plain_elements() {
+        let data = xmp(concat!(
+            "dc:title=\"Jorge &amp; the box\">",
+            "<dc:description>&lt;3 &quot;naps&quot; &apos;n&apos; stuff</dc:description>",
+        ));
+
+        assert_eq!(
+            Caption::from_xmp(&data),
+            Caption {
+                title: Some("Jorge & the box".to_owned()),
+                caption: Some("<3 \"naps\" 'n' stuff".to_owned()),
+                alt: None,
+            }
+        );
+    }
+
+    #[test]
+    fn blank_and_missing_fields_are_none() {
+        let data = xmp(
+            "><dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">  </rdf:li></rdf:Alt></dc:title>",
+        );
+
+        assert_eq!(Caption::from_xmp(&data), Caption::default());
+        assert_eq!(Caption::from_xmp(b"no metadata here"), Caption::default());
+    }
+
+    #[test]
+    fn ignores_malformed_xmp() {
+        for properties in [
+            "><dc:title><rdf:li</rdf:li></dc:title>",
+            "><dc:title><rdf:li>Jorge</dc:title>",
+            "><dc:title><rdf:li",
+            "dc:title=\"Jorge>",
+        ] {
+            assert_eq!(
+                Caption::from_xmp(&xmp(properties)).title,
+                None,
+                "{}",
+                properties
+            );
+        }
+        // The packet is never closed
+        assert_eq!(
+            Caption::from_xmp(b"<x:xmpmeta><dc:title>Jorge</dc:title>"),
+            Caption::default()
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use crate::image_cache::image::Image;
+use crate::image_cache::storage::{read_json, write_json_atomic};
+
+use chrono::{DateTime, Datelike, Days, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::{debug, error, info, warn};
+use rand::{SeedableRng, rngs::StdRng, seq::IndexedRandom};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+};
+
+/// How the image served by /daily is chosen
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DailyPolicy {
+    /// The most recently taken image at the first request of the day
+    #[default]
+    Newest,
+    /// A random image, seeded by the date so every request on a day agrees
+    Random,
+    /// Every image once in a random order before any of them repeats
+    RoundRobin,
+    /// Image IDs listed per date in the schedule file, falling back to the newest image
+    Schedule,
+}
+
+/// Every past pick and the round-robin progress, persisted between restarts
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct DailyState {
+    history: BTreeMap<NaiveDate, String>,
+    shown: HashSet<String>,
+}
+
+pub struct DailyPicker {
+    policy: DailyPolicy,
+    schedule_path: PathBuf,
+    state_path: PathBuf,
+    state: DailyState,
+}
+
+impl DailyPicker {
+    pub fn new(policy: DailyPolicy, schedule_path: &str, state_path: &str) -> Self {
+        info!("Picking the daily image with the {:?} policy", policy);
+
+        let state_path = PathBuf::from(state_path);
+        let state = read_json(&state_path, "Daily picks");
+
+        Self {
+            policy,
+            schedule_path: PathBuf::from(schedule_path),
+            state_path,
+            state,
+        }
+    }
+
+    /// Returns the ID of the image to show on `date`
+    pub fn pick(&mut self, date: NaiveDate, images: &HashMap<&str, &Image>) -> Option<String> {
+        // Every policy sticks to its first pick of the day, so the image never changes mid-day
+        let current = self
+            .state
+            .history
+            .get(&date)
+            .filter(|id| images.contains_key(id.as_str()));
+        if let Some(id) = current {
+            return Some(id.to_owned());
+        }
+
+        let id = match self.policy {
+            DailyPolicy::Newest => newest(images),
+            DailyPolicy::Random => random(date, sorted_ids(images.keys().copied())),
+            DailyPolicy::RoundRobin => self.next_unshown(date, images),
+            DailyPolicy::Schedule => self.scheduled(date, images).or_else(|| newest(images)),
+        }?;
+
+        debug!("Picked {} as the daily image for {}", id, date);
+        self.record(date, &id);
+        Some(id)
+    }
+
+    /// The image that was picked on `date`, if any
+    pub fn picked_on(&self, date: NaiveDate) -> Option<String> {
+        self.state.history.get(&date).cloned()
+    }
+
+    /// Every pick between `from` and `to`, inclusive
+    pub fn history(&self, from: NaiveDate, to: NaiveDate) -> Vec<(NaiveDate, String)> {
+        if from > to {
+            return Vec::new();
+        }
+
+        self.state
+            .history
+            .range(from..=to)
+            .map(|(date, id)| (*date, id.to_owned()))
+            .collect()
+    }
+
+    fn record(&mut self, date: NaiveDate, id: &str) {
+        if self
+            .state
+            .history
+            .get(&date)
+            .is_some_and(|picked| picked == id)
+        {
+            return;
+        }
+
+        self.state.history.insert(date, id.to_owned());
+        if let Err(e) = write_json_atomic(&self.state_path, &self.state) {
+            error!("Error saving daily image state: {}", e);
+        }
+    }
+
+    fn next_unshown(&mut self, date: NaiveDate, images: &HashMap<&str, &Image>) -> Option<String> {
+        self.state
+            .shown
+            .retain(|id| images.contains_key(id.as_str()));
+
+        let mut candidates = sorted_ids(
+            images
+                .keys()
+                .copied()
+                .filter(|id| !self.state.shown.contains(*id)),
+        );
+        if candidates.is_empty() {
+            debug!("Every image has been shown, starting a new round");
+            self.state.shown.clear();
+            candidates = sorted_ids(images.keys().copied());
+        }
+
+        let id = random(date, candidates)?;
+        self.state.shown.insert(id.clone());
+        Some(id)
+    }
+
+    /// Reads the schedule every time a new day starts so edits apply without a restart
+    fn scheduled(&self, date: NaiveDate, images: &HashMap<&str, &Image>) -> Option<String> {
+        let data = std::fs::read(&self.schedule_path)
+            .map_err(|e| warn!("Cannot read schedule {:#?}: {}", self.schedule_path, e))
+            .ok()?;
+        let schedule: HashMap<NaiveDate, String> = serde_json::from_slice(&data)
+            .map_err(|e| warn!("Schedule {:#?} is invalid: {}", self.schedule_path, e))
+            .ok()?;
+
+        let id = schedule.get(&date)?;
+        if !images.contains_key(id.as_str()) {
+            warn!("Scheduled image {} for {} does not exist", id, date);
+            return None;
+        }
+
+        Some(id.to_owned())
+    }
+}
+
+/// The calendar date in `timezone` at `now`
+pub fn local_date(timezone: &Tz, now: DateTime<Utc>) -> NaiveDate {
+    now.with_timezone(timezone).date_naive()
+}
+
+/// The first instant of `date` in `timezone`
+pub fn start_of_day(timezone: &Tz, date: NaiveDate) -> Option<DateTime<Utc>> {
+    // Some zones skip midnight when DST starts, the day then begins at the first valid hour
+    (0..24)
+        .filter_map(|hour| date.and_hms_opt(hour, 0, 0))
+        .find_map(|start| timezone.from_local_datetime(&start).earliest())
+        .map(|start| start.with_timezone(&Utc))
+}
+
+/// The first instant of the day after `now` in `timezone`
+pub fn next_rollover(timezone: &Tz, now: DateTime<Utc>) -> DateTime<Utc> {
+    let tomorrow = local_date(timezone, now)
+        .checked_add_days(Days::new(1))
+        .unwrap_or(NaiveDate::MAX);
+
+    start_of_day(timezone, tomorrow).unwrap_or(now)
+}
+
+fn newest(images: &HashMap<&str, &Image>) -> Option<String> {
+    images
+        .iter()
+        .max_by_key(|(_key, img)| img.image_age)
+        .map(|(key, _)| (*key).to_owned())
+}
+
+/// IDs in a stable order, so a seeded pick is the same across restarts
+fn sorted_ids<'a>(ids: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut ids: Vec<&str> = ids.collect();
+    ids.sort();
+    ids
+}
+
+fn random(date: NaiveDate, ids: Vec<&str>) -> Option<String> {
+    let mut rng = StdRng::seed_from_u64(date.num_days_from_ce() as u64);
+    ids.choose(&mut rng).map(|id| (*id).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn by_id(images: &[Image]) -> HashMap<&str, &Image> {
+        images.iter().map(|img| (img.hash.as_str(), img)).collect()
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 3, day).unwrap()
+    }
+
+    /// An empty directory for the state and schedule of one test
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jorge-daily-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn picker(policy: DailyPolicy, dir: &Path) -> DailyPicker {
+        DailyPicker::new(
+            policy,
+            dir.join("schedule.json").to_str().unwrap(),
+            dir.join("daily.json").to_str().unwrap(),
+        )
+    }
+
+    #[test]
+    fn newest_picks_the_most_recent_image() {
+        let dir = state_dir("newest");
+        let images = [
+            Image::test_dated("a", date(1)),
+            Image::test_dated("c", date(3)),
+            Image::test_dated("b", date(2)),
+        ];
+
+        let mut picker = picker(DailyPolicy::Newest, &dir);
+        assert_eq!(picker.pick(date(1), &by_id(&images)).as_deref(), Some("c"));
+        assert_eq!(picker.pick(date(1), &HashMap::new()), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn random_is_the_same_for_a_date() {
+        let images: Vec<Image> = (1..=20)
+            .map(|day| Image::test_dated(&day.to_string(), date(day)))
+            .collect();
+
+        let picks: Vec<Option<String>> = ["random-a", "random-b"]
+            .iter()
+            .map(|name| {
+                let dir = state_dir(name);
+                let pick = picker(DailyPolicy::Random, &dir).pick(date(1), &by_id(&images));
+                std::fs::remove_dir_all(dir).unwrap();
+                pick
+            })
+            .collect();
+        assert!(picks[0].is_some());
+        assert_eq!(picks[0], picks[1]);
+
+        let dates: HashSet<String> = (1..=10)
+            .filter_map(|day| random(date(day), sorted_ids(by_id(&images).keys().copied())))
+            .collect();
+        assert!(dates.len() > 1);
+    }
+
+    #[test]
+    fn round_robin_shows_every_image_before_repeating() {
+        let dir = state_dir("round-robin");
+        let images = [
+            Image::test_dated("a", date(1)),
+            Image::test_dated("b", date(2)),
+            Image::test_dated("c", date(3)),
+        ];
+        let images = by_id(&images);
+
+        let mut picker = picker(DailyPolicy::RoundRobin, &dir);
+        let round: HashSet<String> = (1..=3)
+            .filter_map(|day| picker.pick(date(day), &images))
+            .collect();
+        assert_eq!(round.len(), 3);
+
+        let next = picker.pick(date(4), &images).unwrap();
+        assert!(images.contains_key(next.as_str()));
+        assert_eq!(picker.state.shown, HashSet::from([next]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn schedule_falls_back_to_the_newest_image() {
+        let dir = state_dir("schedule");
+        let images = [
+            Image::test_dated("a", date(1)),
+            Image::test_dated("b", date(2)),
+        ];
+        let images = by_id(&images);
+        std::fs::write(
+            dir.join("schedule.json"),
+            r#"{"2026-03-01": "a", "2026-03-02": "missing"}"#,
+        )
+        .unwrap();
+
+        let mut picker = picker(DailyPolicy::Schedule, &dir);
+        assert_eq!(picker.pick(date(1), &images).as_deref(), Some("a"));
+        assert_eq!(picker.pick(date(2), &images).as_deref(), Some("b"));
+        assert_eq!(picker.pick(date(3), &images).as_deref(), Some("b"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sticks_to_the_first_pick_of_a_day() {
+        let dir = state_dir("sticky");
+        let mut images = vec![
+            Image::test_dated("a", date(1)),
+            Image::test_dated("b", date(2)),
+        ];
+
+        let mut first = picker(DailyPolicy::Newest, &dir);
+        assert_eq!(first.pick(date(1), &by_id(&images)).as_deref(), Some("b"));
+
+        images.push(Image::test_dated("c", date(3)));
+        assert_eq!(first.pick(date(1), &by_id(&images)).as_deref(), Some("b"));
+        assert_eq!(first.pick(date(2), &by_id(&images)).as_deref(), Some("c"));
+
+        // Picks survive a restart, and a deleted pick is replaced
+        let mut restarted = picker(DailyPolicy::Newest, &dir);
+        assert_eq!(restarted.picked_on(date(1)).as_deref(), Some("b"));
+        images.retain(|img| img.hash != "b");
+        assert_eq!(
+            restarted.pick(date(1), &by_id(&images)).as_deref(),
+            Some("c")
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn utc(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn local_date_follows_the_timezone() {
+        let helsinki = chrono_tz::Europe::Helsinki;
+        assert_eq!(local_date(&helsinki, utc(3, 28, 21, 59)), date(28));
+        assert_eq!(local_date(&helsinki, utc(3, 28, 22, 0)), date(29));
+        assert_eq!(local_date(&Tz::UTC, utc(3, 28, 22, 0)), date(28));
+    }
+
+    #[test]
+    fn start_of_day_across_daylight_saving_time() {
+        let helsinki = chrono_tz::Europe::Helsinki;
+        // Clocks go forward at 03:00 on March 29 and back at 04:00 on October 25
+        assert_eq!(start_of_day(&helsinki, date(29)), Some(utc(3, 28, 22, 0)));
+        assert_eq!(start_of_day(&helsinki, date(30)), Some(utc(3, 29, 21, 0)));
+        let october = |day| NaiveDate::from_ymd_opt(2026, 10, day).unwrap();
+        assert_eq!(
+            start_of_day(&helsinki, october(25)),
+            Some(utc(10, 24, 21, 0))
+        );
+        assert_eq!(
+            start_of_day(&helsinki, october(26)),
+            Some(utc(10, 25, 22, 0))
+        );
+
+        // Havana skips midnight on March 8, the day starts at 01:00
+        let havana = chrono_tz::America::Havana;
+        assert_eq!(start_of_day(&havana, date(7)), Some(utc(3, 7, 5, 0)));
+        assert_eq!(start_of_day(&havana, date(8)), Some(utc(3, 8, 5, 0)));
+    }
+
+    #[test]
+    fn next_rollover_is_the_next_local_midnight() {
+        let helsinki = chrono_tz::Europe::Helsinki;
+        assert_eq!(
+            next_rollover(&helsinki, utc(3, 28, 21, 59)),
+            utc(3, 28, 22, 0)
+        );
+        assert_eq!(
+            next_rollover(&helsinki, utc(3, 28, 22, 0)),
+            utc(3, 29, 21, 0)
+        );
+        assert_eq!(
+            next_rollover(&helsinki, utc(10, 25, 12, 0)),
+            utc(10, 25, 22, 0)
+        );
+
+        let havana = chrono_tz::America::Havana;
+        assert_eq!(next_rollover(&havana, utc(3, 7, 12, 0)), utc(3, 8, 5, 0));
+        assert_eq!(next_rollover(&Tz::UTC, utc(3, 7, 12, 0)), utc(3, 8, 0, 0));
+    }
+}
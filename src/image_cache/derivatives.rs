@@ -0,0 +1,433 @@
+use actix_web::web::{self, Bytes};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, error, info, trace};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::OnceCell;
+
+use crate::endpoints::api::schema::CacheStats;
+use crate::image_cache::image::Derivative;
+use crate::image_cache::storage;
+
+/// On-disk store for encoded derivatives, keyed by source hash and encoding parameters.
+pub struct DerivativeStore {
+    directory: PathBuf,
+}
+
+impl DerivativeStore {
+    pub fn new(directory: &str) -> Self {
+        let directory = PathBuf::from(directory);
+        if let Err(e) = std::fs::create_dir_all(&directory) {
+            error!(
+                "Unable to create derivative directory {:#?}: {}",
+                &directory, e
+            );
+        }
+
+        info!("Storing derivatives in {:#?}", &directory);
+        Self { directory }
+    }
+
+    fn path(&self, hash: &str, variant: &str) -> PathBuf {
+        self.directory.join(format!("{}-{}", hash, variant))
+    }
+
+    pub async fn get(&self, hash: &str, variant: &str) -> Option<Bytes> {
+        let path = self.path(hash, variant);
+        let data = web::block(move || std::fs::read(path)).await.ok()?.ok()?;
+        trace!("Loaded derivative {}-{} from disk", hash, variant);
+        Some(Bytes::from(data))
+    }
+
+    /// Path and size of a stored derivative, for streaming it instead of loading it
+    pub async fn locate(&self, hash: &str, variant: &str) -> Option<(PathBuf, u64)> {
+        let path = self.path(hash, variant);
+        let target = path.clone();
+        let metadata = web::block(move || std::fs::metadata(target))
+            .await
+            .ok()?
+            .ok()?;
+        metadata.is_file().then_some((path, metadata.len()))
+    }
+
+    pub async fn put(&self, hash: &str, variant: &str, data: Bytes) {
+        let path = self.path(hash, variant);
+
+        // Readers never see a partial derivative, and every writer gets its own temporary file
+        // so concurrent encodes of the same variant can't interleave
+        let target = path.clone();
+        let result = web::block(move || storage::write_atomic(&target, &data)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Unable to store derivative {:#?}: {}", &path, e),
+            Err(e) => error!("Unable to store derivative {:#?}: {}", &path, e),
+        }
+    }
+
+    /// Removes every derivative and the stripped copy of a source
+    pub async fn remove(&self, hash: &str) {
+        let (directory, prefix) = (self.directory.clone(), format!("{}-", hash));
+        let result = web::block(move || {
+            let mut removed = 0;
+            for entry in std::fs::read_dir(directory)?.filter_map(Result::ok) {
+                if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    std::fs::remove_file(entry.path())?;
+                    removed += 1;
+                }
+            }
+            Ok::<_, std::io::Error>(removed)
+        })
+        .await;
+        match result {
+            Ok(Ok(removed)) => debug!("Removed {} derivatives of {}", removed, hash),
+            Ok(Err(e)) => error!("Unable to remove derivatives of {}: {}", hash, e),
+            Err(e) => error!("Unable to remove derivatives of {}: {}", hash, e),
+        }
+    }
+
+    /// Removes derivatives of sources that are no longer part of the cache
+    pub fn prune(&self, hashes: &HashSet<String>) {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Unable to read derivative directory: {}", e);
+                return;
+            }
+        };
+
+        let mut removed = 0;
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name();
+            let hash = name
+                .to_str()
+                .and_then(|name| name.split_once('-'))
+                .map(|(hash, _)| hash);
+
+            if hash.is_some_and(|hash| !hashes.contains(hash))
+                && std::fs::remove_file(entry.path()).is_ok()
+            {
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            debug!("Pruned {} stale derivatives from disk.", removed);
+        }
+    }
+}
+
+struct CachedDerivative {
+    data: Arc<OnceCell<Bytes>>,
+    last_used: DateTime<Utc>,
+    /// Bytes accounted against the budget, zero until the derivative has been resolved
+    size: usize,
+}
+
+#[derive(Default)]
+struct DerivativeEntries {
+    entries: HashMap<(String, Derivative), CachedDerivative>,
+    used_bytes: usize,
+}
+
+impl DerivativeEntries {
+    fn remove(&mut self, key: &(String, Derivative)) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used_bytes -= entry.size;
+        }
+    }
+}
+
+/// In-memory derivatives kept within a byte budget, least recently used ones are evicted first.
+/// Concurrent requests for the same derivative share a single encode.
+pub struct DerivativeCache {
+    state: Mutex<DerivativeEntries>,
+    budget_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl DerivativeCache {
+    pub fn new(budget_bytes: usize) -> Self {
+        info!(
+            "Keeping up to {} bytes of derivatives in memory",
+            budget_bytes
+        );
+        Self {
+            state: Mutex::new(DerivativeEntries::default()),
+            budget_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, DerivativeEntries> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the cached derivative, running `init` once if it is not present yet
+    pub async fn get_or_try_init<F, Fut>(
+        &self,
+        hash: &str,
+        derivative: &Derivative,
+        init: F,
+    ) -> Result<Bytes, anyhow::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Bytes, anyhow::Error>>,
+    {
+        let key = (hash.to_string(), *derivative);
+        let data = {
+            let mut state = self.lock_state();
+            let entry = state
+                .entries
+                .entry(key.clone())
+                .or_insert_with(|| CachedDerivative {
+                    data: Arc::new(OnceCell::new()),
+                    last_used: Utc::now(),
+                    size: 0,
+                });
+            entry.last_used = Utc::now();
+            Arc::clone(&entry.data)
+        };
+
+        if let Some(bytes) = data.get() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(bytes.clone());
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let bytes = data.get_or_try_init(init).await?.clone();
+        self.account(&key, bytes.len());
+        Ok(bytes)
+    }
+
+    /// Charges a freshly resolved derivative against the budget and evicts until it fits
+    fn account(&self, key: &(String, Derivative), size: usize) {
+        let mut state = self.lock_state();
+        match state.entries.get_mut(key) {
+            Some(entry) if entry.size == 0 => entry.size = size,
+            _ => return,
+        }
+
+        // A single derivative larger than the whole budget is not kept, nor does it evict others
+        if size > self.budget_bytes {
+            state.entries.remove(key);
+            return;
+        }
+        state.used_bytes += size;
+
+        while state.used_bytes > self.budget_bytes {
+            let oldest = state
+                .entries
+                .iter()
+                .filter(|(entry_key, entry)| *entry_key != key && entry.size > 0)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(entry_key, _)| entry_key.clone());
+
+            let Some(oldest) = oldest else {
+                break;
+            };
+            trace!("Evicting derivative {} {}", oldest.0, oldest.1.variant());
+            state.remove(&oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops derivatives that have not been used for `max_idle`, returns how many were dropped
+    pub fn clean(&self, max_idle: TimeDelta) -> usize {
+        let mut state = self.lock_state();
+        let now = Utc::now();
+
+        // Entries that are still being encoded are kept so waiting requests share the result,
+        // failed encodes leave an empty entry behind that nobody holds on to
+        let expired: Vec<(String, Derivative)> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                if entry.data.initialized() {
+                    now - entry.last_used > max_idle
+                } else {
+                    Arc::strong_count(&entry.data) == 1
+                }
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            state.remove(key);
+        }
+
+        expired.len()
+    }
+
+    /// Drops every derivative of an image
+    pub fn remove(&self, hash: &str) {
+        let mut state = self.lock_state();
+        let keys: Vec<(String, Derivative)> = state
+            .entries
+            .keys()
+            .filter(|(entry_hash, _)| entry_hash == hash)
+            .cloned()
+            .collect();
+
+        for key in &keys {
+            state.remove(key);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.lock_state();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: state.entries.len(),
+            used_bytes: state.used_bytes,
+            budget_bytes: self.budget_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_cache::image::{Fit, OutputFormat, Resize};
+    use anyhow::anyhow;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn derivative(width: u32) -> Derivative {
+        Derivative {
+            format: OutputFormat::WebP,
+            resize: Some(Resize {
+                width: Some(width),
+                height: None,
+                fit: Fit::Contain,
+            }),
+        }
+    }
+
+    /// Resolves a derivative of `size` bytes, returns whether it had to be encoded
+    async fn fetch(cache: &DerivativeCache, hash: &str, width: u32, size: usize) -> bool {
+        let mut encoded = false;
+        cache
+            .get_or_try_init(hash, &derivative(width), || async {
+                encoded = true;
+                Ok(Bytes::from(vec![0; size]))
+            })
+            .await
+            .unwrap();
+        encoded
+    }
+
+    /// Successive uses get distinct timestamps
+    fn tick() {
+        std::thread::sleep(Duration::from_millis(2));
+    }
+
+    #[actix_web::test]
+    async fn removes_every_derivative_of_a_source() {
+        let directory =
+            std::env::temp_dir().join(format!("jorge-derivatives-{}", std::process::id()));
+        let store = DerivativeStore::new(directory.to_str().unwrap());
+        for (hash, variant) in [("a", "webp"), ("a", "stripped"), ("ab", "webp")] {
+            store.put(hash, variant, Bytes::from_static(b"data")).await;
+        }
+
+        // Temporary files are renamed into place
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 3);
+
+        store.remove("a").await;
+        assert!(store.locate("a", "webp").await.is_none());
+        assert!(store.locate("a", "stripped").await.is_none());
+        assert!(store.locate("ab", "webp").await.is_some());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn evicts_the_least_recently_used() {
+        let cache = DerivativeCache::new(10);
+        fetch(&cache, "a", 1, 4).await;
+        tick();
+        fetch(&cache, "b", 1, 4).await;
+        tick();
+        assert!(!fetch(&cache, "a", 1, 4).await);
+        tick();
+        fetch(&cache, "c", 1, 4).await;
+
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.entries, stats.used_bytes, stats.evictions),
+            (2, 8, 1)
+        );
+        assert!(!fetch(&cache, "a", 1, 4).await);
+        assert!(!fetch(&cache, "c", 1, 4).await);
+        assert!(fetch(&cache, "b", 1, 4).await);
+    }
+
+    #[actix_web::test]
+    async fn does_not_keep_derivatives_larger_than_the_budget() {
+        let cache = DerivativeCache::new(10);
+        fetch(&cache, "small", 1, 4).await;
+        assert!(fetch(&cache, "large", 1, 11).await);
+
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.entries, stats.used_bytes, stats.evictions),
+            (1, 4, 0)
+        );
+        assert!(fetch(&cache, "large", 1, 11).await);
+        assert!(!fetch(&cache, "small", 1, 4).await);
+    }
+
+    #[actix_web::test]
+    async fn remove_and_clean_release_their_bytes() {
+        let cache = DerivativeCache::new(100);
+        fetch(&cache, "a", 1, 3).await;
+        fetch(&cache, "a", 2, 2).await;
+        fetch(&cache, "b", 1, 4).await;
+        assert_eq!(cache.stats().used_bytes, 9);
+
+        cache.remove("a");
+        assert_eq!((cache.stats().entries, cache.stats().used_bytes), (1, 4));
+
+        assert_eq!(cache.clean(TimeDelta::hours(1)), 0);
+        tick();
+        assert_eq!(cache.clean(TimeDelta::zero()), 1);
+        assert_eq!((cache.stats().entries, cache.stats().used_bytes), (0, 0));
+    }
+
+    #[actix_web::test]
+    async fn clean_keeps_derivatives_that_are_being_encoded() {
+        let cache = DerivativeCache::new(100);
+        let (done, encoded) = oneshot::channel::<()>();
+        let variant = derivative(1);
+        let mut pending = Box::pin(cache.get_or_try_init("a", &variant, || async {
+            encoded.await?;
+            Ok(Bytes::from_static(b"data"))
+        }));
+        assert!(futures_util::poll!(&mut pending).is_pending());
+
+        tick();
+        assert_eq!(cache.clean(TimeDelta::zero()), 0);
+        done.send(()).unwrap();
+        assert_eq!(pending.await.unwrap(), Bytes::from_static(b"data"));
+        assert_eq!(cache.stats().used_bytes, 4);
+
+        // A failed encode leaves an empty entry that the next clean drops
+        let failed = cache
+            .get_or_try_init("b", &derivative(1), || async { Err(anyhow!("broken")) })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(cache.clean(TimeDelta::hours(1)), 1);
+        assert_eq!((cache.stats().entries, cache.stats().used_bytes), (1, 4));
+    }
+}
@@ -0,0 +1,117 @@
+use actix_web::web;
+use anyhow::anyhow;
+use log::{info, warn};
+use std::{fmt, sync::Arc};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Returned when every encoder is busy and the queue is full
+#[derive(Debug)]
+pub struct EncoderBusy;
+
+impl fmt::Display for EncoderBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Encoder queue is full")
+    }
+}
+
+impl std::error::Error for EncoderBusy {}
+
+/// Runs image decoding and encoding on the blocking thread pool,
+/// with a limit on how many run at once and how many may wait.
+pub struct EncoderPool {
+    running: Arc<Semaphore>,
+    queued: Arc<Semaphore>,
+}
+
+impl EncoderPool {
+    pub fn new(concurrency: usize, queue_depth: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        info!(
+            "Encoding up to {} images at once with {} queued",
+            concurrency, queue_depth
+        );
+
+        Self {
+            running: Arc::new(Semaphore::new(concurrency)),
+            queued: Arc::new(Semaphore::new(concurrency + queue_depth)),
+        }
+    }
+
+    /// Runs `f` on the blocking pool, failing with [`EncoderBusy`] if the queue is full
+    pub async fn run<F, T>(&self, f: F) -> Result<T, anyhow::Error>
+    where
+        F: FnOnce() -> Result<T, anyhow::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let queued = self.queued.clone().try_acquire_owned().map_err(|_| {
+            warn!("Encoder queue is full, rejecting request");
+            EncoderBusy
+        })?;
+
+        self.spawn(Some(queued), f).await
+    }
+
+    /// Runs `f` on the blocking pool, waiting for a free encoder instead of taking a queue slot
+    pub async fn run_background<F, T>(&self, f: F) -> Result<T, anyhow::Error>
+    where
+        F: FnOnce() -> Result<T, anyhow::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn(None, f).await
+    }
+
+    async fn spawn<F, T>(
+        &self,
+        queued: Option<OwnedSemaphorePermit>,
+        f: F,
+    ) -> Result<T, anyhow::Error>
+    where
+        F: FnOnce() -> Result<T, anyhow::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let running = self.running.clone().acquire_owned().await?;
+
+        // The permits go with the encode, a cancelled request does not free its slot early
+        web::block(move || {
+            let _permits = (queued, running);
+            f()
+        })
+        .await
+        .map_err(|e| anyhow!("Encoder task failed: {}", e))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+
+    #[actix_web::test]
+    async fn cancelled_encodes_keep_their_slot() {
+        let pool = EncoderPool::new(1, 0);
+        let (started, encoding) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+
+        let mut encode = Box::pin(pool.run(move || {
+            started.send(()).unwrap();
+            finished.recv()?;
+            Ok(())
+        }));
+        assert!(futures_util::poll!(&mut encode).is_pending());
+        encoding.recv().unwrap();
+        drop(encode);
+
+        let busy = pool.run(|| Ok(())).await.unwrap_err();
+        assert!(busy.is::<EncoderBusy>());
+
+        // The slot frees up once the encode itself is done
+        finish.send(()).unwrap();
+        for _ in 0..100 {
+            if pool.run(|| Ok(())).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Encoder slot was never released");
+    }
+}
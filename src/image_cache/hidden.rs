@@ -0,0 +1,55 @@
+use crate::image_cache::storage::{read_json, write_json_atomic};
+
+use log::{error, info};
+use std::{collections::BTreeSet, path::PathBuf};
+
+/// IDs of images that are kept on disk but not shown anywhere, persisted between restarts
+pub struct HiddenImages {
+    path: PathBuf,
+    ids: BTreeSet<String>,
+}
+
+impl HiddenImages {
+    pub fn load(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        let ids: BTreeSet<String> = read_json(&path, "Hidden images");
+
+        if !ids.is_empty() {
+            info!("Hiding {} images", ids.len());
+        }
+
+        Self { path, ids }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.ids.iter().cloned().collect()
+    }
+
+    /// Returns whether the set changed
+    pub fn hide(&mut self, id: &str) -> bool {
+        let changed = self.ids.insert(id.to_owned());
+        if changed {
+            self.persist();
+        }
+        changed
+    }
+
+    /// Returns whether the set changed
+    pub fn unhide(&mut self, id: &str) -> bool {
+        let changed = self.ids.remove(id);
+        if changed {
+            self.persist();
+        }
+        changed
+    }
+
+    fn persist(&self) {
+        if let Err(e) = write_json_atomic(&self.path, &self.ids) {
+            error!("Error saving hidden images: {}", e);
+        }
+    }
+}
@@ -1,18 +1,250 @@
 use actix_web::mime;
 use anyhow::anyhow;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use chrono_tz::Tz;
 use exif::{Reader, Tag};
-use std::{path::PathBuf, str::FromStr};
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{File, Metadata},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::image_cache::captions::Caption;
+use crate::image_cache::index::IndexEntry;
+use crate::image_cache::privacy::{self, Stripping};
+use crate::image_cache::segments::{Container, SegmentWalker};
+use crate::image_cache::tags;
 
 const COMPRESSION_LEVEL: f32 = 0.82;
+/// How much of a file without known metadata blocks is searched for XMP
+const METADATA_SCAN_LIMIT: u64 = 1024 * 1024;
+const AVIF_QUALITY: u8 = 70;
+const AVIF_SPEED: u8 = 8;
+const JPEG_QUALITY: u8 = 82;
+
+/// How a resized image fills the requested box
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale down until the whole image fits inside the box
+    #[default]
+    Contain,
+    /// Scale and crop so the image covers the whole box
+    Cover,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Resize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+}
+
+impl Resize {
+    fn apply(&self, img: image::DynamicImage) -> image::DynamicImage {
+        let width = self.width.unwrap_or(u32::MAX);
+        let height = self.height.unwrap_or(u32::MAX);
+
+        // Never upscale
+        if img.width() <= width && img.height() <= height {
+            return img;
+        }
+
+        match (self.fit, self.width, self.height) {
+            (Fit::Cover, Some(width), Some(height)) => {
+                // Shrink the box to fit inside the image, keeping its shape, so filling it
+                // only ever crops
+                let ratio = (img.width() as f64 / width as f64)
+                    .min(img.height() as f64 / height as f64)
+                    .min(1.0);
+                let width = ((width as f64 * ratio) as u32).max(1);
+                let height = ((height as f64 * ratio) as u32).max(1);
+                img.resize_to_fill(width, height, FilterType::CatmullRom)
+            }
+            _ => img.resize(width, height, FilterType::CatmullRom),
+        }
+    }
+
+    fn variant(&self) -> String {
+        let fit = match self.fit {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        };
+
+        format!(
+            "-w{}-h{}-{}",
+            self.width.unwrap_or(0),
+            self.height.unwrap_or(0),
+            fit
+        )
+    }
+}
+
+/// Formats derivatives can be encoded to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Avif,
+    WebP,
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Jpeg => "image/jpeg",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "avif",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Jpeg => "jpg",
+        }
+    }
+
+    fn quality(&self) -> u32 {
+        match self {
+            OutputFormat::Avif => AVIF_QUALITY as u32,
+            OutputFormat::WebP => (COMPRESSION_LEVEL * 100.0).round() as u32,
+            OutputFormat::Jpeg => JPEG_QUALITY as u32,
+        }
+    }
+}
+
+/// An encoded, optionally resized, version of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Derivative {
+    pub format: OutputFormat,
+    pub resize: Option<Resize>,
+}
+
+impl Derivative {
+    /// Name of the derivative on disk, changes whenever the encoding parameters do
+    pub fn variant(&self) -> String {
+        format!(
+            "{}-q{}{}.{}",
+            self.format.extension(),
+            self.format.quality(),
+            self.resize
+                .map(|resize| resize.variant())
+                .unwrap_or_default(),
+            self.format.extension()
+        )
+    }
+}
+
+/// Where the date an image was taken is read from
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DateSource {
+    /// EXIF DateTimeOriginal, when the shutter fired
+    DateTimeOriginal,
+    /// EXIF CreateDate (DateTimeDigitized), when the picture was stored
+    CreateDate,
+    /// Filesystem birth time, reset by copying and missing on some filesystems
+    Created,
+    /// Filesystem modification time
+    Modified,
+}
+
+/// EXIF details that may be exposed through the API, location is deliberately not one of them
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetaField {
+    Width,
+    Height,
+    Camera,
+    Lens,
+    Exposure,
+    Iso,
+    FocalLength,
+}
+
+/// Shot details read from EXIF when an image is indexed
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ShotDetails {
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub exposure: Option<String>,
+    pub iso: Option<u32>,
+    pub focal_length: Option<String>,
+}
+
+impl ShotDetails {
+    fn from_exif(exif: &exif::Exif) -> Self {
+        let field = |tag: Tag| exif.get_field(tag, exif::In::PRIMARY);
+        let ascii = |tag: Tag| match &field(tag)?.value {
+            exif::Value::Ascii(values) => values
+                .first()
+                .map(|value| {
+                    String::from_utf8_lossy(value)
+                        .trim_matches(['\0', ' '])
+                        .to_owned()
+                })
+                .filter(|value| !value.is_empty()),
+            _ => None,
+        };
+        let with_unit =
+            |tag: Tag| field(tag).map(|field| field.display_value().with_unit(exif).to_string());
+
+        // Most cameras already repeat the make in the model
+        let camera = match (ascii(Tag::Make), ascii(Tag::Model)) {
+            (Some(make), Some(model)) if !model.starts_with(&make) => {
+                Some(format!("{} {}", make, model))
+            }
+            (_, Some(model)) => Some(model),
+            (make, None) => make,
+        };
+
+        Self {
+            camera,
+            lens: ascii(Tag::LensModel),
+            exposure: with_unit(Tag::ExposureTime),
+            iso: field(Tag::PhotographicSensitivity).and_then(|field| field.value.get_uint(0)),
+            focal_length: with_unit(Tag::FocalLength),
+        }
+    }
+}
+
+/// Returned when uploaded data is not an image type that can be served
+#[derive(Debug)]
+pub struct UnsupportedImage;
+
+impl fmt::Display for UnsupportedImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsupported image type")
+    }
+}
+
+impl std::error::Error for UnsupportedImage {}
 
 #[derive(Clone, Debug)]
 pub struct Image {
     pub path: PathBuf,
-    pub data: Vec<u8>,
-    pub compressed_data: Vec<u8>,
     pub image_age: DateTime<Utc>,
-    cache_time: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+    pub size: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub hash: String,
+    pub details: ShotDetails,
+    /// Caption shown with the image, see `Cache` for where it comes from
+    pub caption: Caption,
+    /// Caption from the metadata embedded in the file
+    pub embedded_caption: Caption,
+    /// Tags shown with the image, see `Cache` for where they come from
+    pub tags: Vec<String>,
+    /// Keywords from the metadata embedded in the file
+    pub embedded_tags: Vec<String>,
+    /// Other files with the same contents, one of them is served once `path` is gone
+    pub copies: Vec<PathBuf>,
     image_type: imghdr::Type,
 }
 
@@ -49,56 +281,245 @@ impl Image {
             .ok_or_else(|| anyhow!("Invalid orientation value"))
     }
 
-    fn compress_image(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
-        let img = image::load_from_memory(data)?;
+    /// How metadata is removed from the original before it is served
+    pub fn stripping(&self) -> Stripping {
+        match self.image_type {
+            imghdr::Type::Bmp | imghdr::Type::Ico => Stripping::Unneeded,
+            imghdr::Type::Jpeg | imghdr::Type::Png | imghdr::Type::Webp | imghdr::Type::Gif => {
+                Stripping::Rewrite
+            }
+            _ => Stripping::Reencode,
+        }
+    }
 
-        let orientation = Self::get_exif_orientation(data).unwrap_or(1);
-        let rotated = Self::apply_exif_orientation(img, orientation);
+    /// Reads the original with location and other identifying metadata removed
+    pub fn read_stripped(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let data = std::fs::read(&self.path)?;
+        privacy::strip_metadata(&data)
+    }
 
-        let encoder = webp::Encoder::from_image(&rotated)
-            .map_err(|err| anyhow!("Error parsing file: {}", err))?;
+    /// Reads an EXIF date, honouring its offset tag and assuming `timezone` without one
+    fn get_exif_date(
+        exif: &exif::Exif,
+        tag: Tag,
+        offset_tag: Tag,
+        timezone: &Tz,
+    ) -> Option<DateTime<Utc>> {
+        let ascii = |tag: Tag| match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+            exif::Value::Ascii(values) => values.first().cloned(),
+            _ => None,
+        };
 
-        encoder
-            .encode_simple(false, COMPRESSION_LEVEL)
-            .map_err(|err| anyhow!("Error encoding data: {:#?}", err))
-            .map(|mem| mem.to_vec())
+        let mut date = exif::DateTime::from_ascii(&ascii(tag)?).ok()?;
+        if let Some(offset) = ascii(offset_tag) {
+            let _ = date.parse_offset(&offset);
+        }
+
+        let local = NaiveDate::from_ymd_opt(date.year.into(), date.month.into(), date.day.into())?
+            .and_hms_opt(date.hour.into(), date.minute.into(), date.second.into())?;
+        match date.offset {
+            Some(offset) => local
+                .and_local_timezone(FixedOffset::east_opt(i32::from(offset) * 60)?)
+                .single()
+                .map(|date| date.with_timezone(&Utc)),
+            // Cameras write their local time, repeated times around DST changes take the first
+            None => local
+                .and_local_timezone(*timezone)
+                .earliest()
+                .map(|date| date.with_timezone(&Utc)),
+        }
     }
 
-    fn resolve_compressed(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
-        Self::compress_image(&data)
+    /// Picks the first date that is available in `sources`
+    fn image_date(
+        exif: Option<&exif::Exif>,
+        metadata: &Metadata,
+        sources: &[DateSource],
+        timezone: &Tz,
+    ) -> Result<DateTime<Utc>, anyhow::Error> {
+        sources
+            .iter()
+            .find_map(|source| match source {
+                DateSource::DateTimeOriginal => exif.and_then(|exif| {
+                    Self::get_exif_date(
+                        exif,
+                        Tag::DateTimeOriginal,
+                        Tag::OffsetTimeOriginal,
+                        timezone,
+                    )
+                }),
+                DateSource::CreateDate => exif.and_then(|exif| {
+                    Self::get_exif_date(
+                        exif,
+                        Tag::DateTimeDigitized,
+                        Tag::OffsetTimeDigitized,
+                        timezone,
+                    )
+                }),
+                DateSource::Created => metadata.created().ok().map(DateTime::from),
+                DateSource::Modified => metadata.modified().ok().map(DateTime::from),
+            })
+            .ok_or_else(|| anyhow!("No image date available"))
     }
-}
 
-impl Image {
-    /// Returns cache age in ms
-    pub fn cache_age(&self) -> i64 {
-        let now = Utc::now();
-        (now - self.cache_time).num_milliseconds()
+    /// Reads an image from disk, dating it with the first source that is available.
+    /// EXIF dates without an offset are taken to be in `timezone`.
+    pub fn load(
+        path: &str,
+        date_sources: &[DateSource],
+        timezone: &Tz,
+    ) -> Result<Self, anyhow::Error> {
+        let hash = Self::content_hash(&PathBuf::from(path))?;
+        Self::load_with_hash(path, date_sources, timezone, hash)
     }
 
-    /// Loads image data to the cache and update cache age
-    pub fn resolve(&mut self) -> Result<(), anyhow::Error> {
-        if let Ok(data) = std::fs::read(&self.path) {
-            if self.compressed_is_empty() {
-                self.compressed_data = Self::resolve_compressed(&data)?;
+    /// Like `load`, for callers that already hashed the file contents
+    pub fn load_with_hash(
+        path: &str,
+        date_sources: &[DateSource],
+        timezone: &Tz,
+        hash: String,
+    ) -> Result<Self, anyhow::Error> {
+        let path = PathBuf::from(path).canonicalize()?;
+        let image_type = imghdr::from_file(&path)?.ok_or(anyhow!("File type is not supported"))?;
+        let metadata = path.metadata()?;
+        let exif = File::open(&path).ok().and_then(|file| {
+            Reader::new()
+                .read_from_container(&mut BufReader::new(file))
+                .ok()
+        });
+        // Orientations 5 to 8 rotate by a quarter turn, so the displayed image is transposed
+        let orientation = exif
+            .as_ref()
+            .and_then(|exif| exif.get_field(Tag::Orientation, exif::In::PRIMARY))
+            .and_then(|field| field.value.get_uint(0));
+        let dimensions =
+            image::image_dimensions(&path)
+                .ok()
+                .map(|(width, height)| match orientation {
+                    Some(5..=8) => (height, width),
+                    _ => (width, height),
+                });
+        let header = Self::metadata_blocks(&path)?;
+        let embedded_caption = Caption::from_xmp(&header);
+        let embedded_tags = tags::from_embedded(&header);
+
+        Ok(Self {
+            image_type,
+            image_age: Self::image_date(exif.as_ref(), &metadata, date_sources, timezone)?,
+            details: exif
+                .as_ref()
+                .map(ShotDetails::from_exif)
+                .unwrap_or_default(),
+            caption: embedded_caption.clone(),
+            embedded_caption,
+            tags: embedded_tags.clone(),
+            embedded_tags,
+            copies: Vec::new(),
+            modified: DateTime::from(metadata.modified()?),
+            size: metadata.len(),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            hash,
+            path,
+        })
+    }
+
+    /// Hex encoded SHA-256 of the file contents, used as the image ID
+    fn content_hash(path: &PathBuf) -> Result<String, anyhow::Error> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// The parts of a file that can hold XMP and IPTC metadata, in their original layout.
+    /// JPEG, PNG and WebP image data is skipped, other formats are only searched up to
+    /// `METADATA_SCAN_LIMIT`.
+    fn metadata_blocks(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut out = Vec::new();
+        file.by_ref().take(12).read_to_end(&mut out)?;
+
+        match Container::detect(&out) {
+            Some(container) => {
+                out.truncate(container.start());
+                Self::segment_metadata(file, container, &mut out);
+            }
+            None => {
+                file.take(METADATA_SCAN_LIMIT - 12).read_to_end(&mut out)?;
             }
-            self.data = data;
-            self.cache_time = Utc::now();
-            return Ok(());
         }
-        Err(anyhow!("Unable to read image data"))
+        Ok(out)
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
+    /// Copies every segment apart from the image data, a broken segment ends the scan
+    fn segment_metadata(file: BufReader<File>, container: Container, out: &mut Vec<u8>) {
+        let Ok(mut segments) = SegmentWalker::new(file, container) else {
+            return;
+        };
+        while let Ok(Some(segment)) = segments.next_segment() {
+            if segment.is_image_data() {
+                continue;
+            }
+            out.extend_from_slice(&segment.header);
+            if segments.read_data(out).is_err() {
+                break;
+            }
+        }
     }
 
-    pub fn compressed_is_empty(&self) -> bool {
-        self.compressed_data.is_empty()
+    fn compress_image(data: &[u8], derivative: &Derivative) -> Result<Vec<u8>, anyhow::Error> {
+        let img = image::load_from_memory(data)?;
+
+        let orientation = Self::get_exif_orientation(data).unwrap_or(1);
+        let rotated = Self::apply_exif_orientation(img, orientation);
+        let resized = match &derivative.resize {
+            Some(resize) => resize.apply(rotated),
+            None => rotated,
+        };
+
+        match derivative.format {
+            OutputFormat::WebP => {
+                let encoder = webp::Encoder::from_image(&resized)
+                    .map_err(|err| anyhow!("Error parsing file: {}", err))?;
+
+                encoder
+                    .encode_simple(false, COMPRESSION_LEVEL)
+                    .map_err(|err| anyhow!("Error encoding data: {:#?}", err))
+                    .map(|mem| mem.to_vec())
+            }
+            OutputFormat::Avif => {
+                let mut buffer = Vec::new();
+                let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                    &mut buffer,
+                    AVIF_SPEED,
+                    AVIF_QUALITY,
+                );
+                image::DynamicImage::ImageRgba8(resized.to_rgba8())
+                    .write_with_encoder(encoder)
+                    .map_err(|err| anyhow!("Error encoding data: {:#?}", err))?;
+                Ok(buffer)
+            }
+            OutputFormat::Jpeg => {
+                // JPEG has no alpha channel
+                let mut buffer = Vec::new();
+                let encoder =
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY);
+                image::DynamicImage::ImageRgb8(resized.to_rgb8())
+                    .write_with_encoder(encoder)
+                    .map_err(|err| anyhow!("Error encoding data: {:#?}", err))?;
+                Ok(buffer)
+            }
+        }
     }
+}
 
-    pub fn clear(&mut self) {
-        self.data.clear();
+impl Image {
+    /// Reads the original from disk and encodes the requested derivative
+    pub fn encode(&self, derivative: &Derivative) -> Result<Vec<u8>, anyhow::Error> {
+        let source = std::fs::read(&self.path).map_err(|_| anyhow!("Unable to read image data"))?;
+        Self::compress_image(&source, derivative)
     }
 
     pub fn content_type(&self) -> String {
@@ -114,21 +535,306 @@ impl Image {
             _ => "application/octet-stream".to_string(),
         }
     }
-}
 
-impl FromStr for Image {
-    type Err = anyhow::Error;
+    pub fn has_any_tag(&self, tags: &[String]) -> bool {
+        tags.iter().any(|tag| self.tags.contains(tag))
+    }
+
+    /// Every file with the contents of this image, starting with the one that is served
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.path).chain(&self.copies)
+    }
 
-    fn from_str(path: &str) -> Result<Self, Self::Err> {
-        let path = PathBuf::from(path).canonicalize()?;
+    pub fn has_path(&self, path: &Path) -> bool {
+        self.paths().any(|known| known == path)
+    }
+
+    /// Forgets the file at `path`, a copy takes over if it was the one being served.
+    /// Returns whether any file is left.
+    pub fn remove_path(&mut self, path: &Path) -> bool {
+        if self.path != path {
+            self.copies.retain(|copy| copy != path);
+            return true;
+        }
+        if self.copies.is_empty() {
+            return false;
+        }
+        self.path = self.copies.remove(0);
+        true
+    }
+
+    /// File extension for image data of a supported type
+    pub fn extension_for(data: &[u8]) -> Option<&'static str> {
+        // imghdr indexes into the first 12 bytes without checking the length
+        if data.len() < 12 {
+            return None;
+        }
+
+        Some(match imghdr::from_bytes(data)? {
+            imghdr::Type::Gif => "gif",
+            imghdr::Type::Tiff => "tiff",
+            imghdr::Type::Jpeg => "jpg",
+            imghdr::Type::Bmp => "bmp",
+            imghdr::Type::Png => "png",
+            imghdr::Type::Webp => "webp",
+            imghdr::Type::Exr => "exr",
+            imghdr::Type::Ico => "ico",
+            _ => return None,
+        })
+    }
+
+    fn image_type_from_content_type(content_type: &str) -> Option<imghdr::Type> {
+        Some(match content_type {
+            "image/gif" => imghdr::Type::Gif,
+            "image/tiff" => imghdr::Type::Tiff,
+            "image/jpeg" => imghdr::Type::Jpeg,
+            "image/bmp" => imghdr::Type::Bmp,
+            "image/png" => imghdr::Type::Png,
+            "image/webp" => imghdr::Type::Webp,
+            "image/exr" => imghdr::Type::Exr,
+            "image/vnd.microsoft.icon" => imghdr::Type::Ico,
+            _ => return None,
+        })
+    }
+
+    pub fn to_index_entry(&self) -> IndexEntry {
+        IndexEntry {
+            path: self.path.clone(),
+            content_type: self.content_type(),
+            image_age: self.image_age,
+            modified: self.modified,
+            size: self.size,
+            width: self.width,
+            height: self.height,
+            hash: self.hash.clone(),
+            details: self.details.clone(),
+            caption: self.embedded_caption.clone(),
+            keywords: self.embedded_tags.clone(),
+        }
+    }
+}
+
+impl TryFrom<&IndexEntry> for Image {
+    type Error = anyhow::Error;
 
+    fn try_from(entry: &IndexEntry) -> Result<Self, Self::Error> {
         Ok(Self {
-            image_type: imghdr::from_file(&path)?.ok_or(anyhow!("File type is not supported"))?,
-            image_age: DateTime::from(path.metadata()?.created()?),
-            path: path,
-            cache_time: Utc::now(),
-            data: Vec::new(),
-            compressed_data: Vec::new(),
+            image_type: Self::image_type_from_content_type(&entry.content_type)
+                .ok_or(anyhow!("File type is not supported"))?,
+            path: entry.path.clone(),
+            image_age: entry.image_age,
+            modified: entry.modified,
+            size: entry.size,
+            width: entry.width,
+            height: entry.height,
+            hash: entry.hash.clone(),
+            details: entry.details.clone(),
+            caption: entry.caption.clone(),
+            embedded_caption: entry.caption.clone(),
+            tags: entry.keywords.clone(),
+            embedded_tags: entry.keywords.clone(),
+            copies: Vec::new(),
         })
     }
 }
+
+#[cfg(test)]
+impl Image {
+    /// A PNG image with ID `id` that was taken at noon UTC on `date`
+    pub fn test_dated(id: &str, date: NaiveDate) -> Image {
+        let date = date.and_hms_opt(12, 0, 0).unwrap().and_utc();
+        Self {
+            path: format!("/images/{}.png", id).into(),
+            image_age: date,
+            modified: date,
+            size: 1,
+            width: None,
+            height: None,
+            hash: id.to_owned(),
+            details: ShotDetails::default(),
+            caption: Caption::default(),
+            embedded_caption: Caption::default(),
+            tags: Vec::new(),
+            embedded_tags: Vec::new(),
+            copies: Vec::new(),
+            image_type: imghdr::Type::Png,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use image::{DynamicImage, ImageFormat, RgbImage};
+
+    fn resized(width: u32, height: u32, resize: Resize) -> (u32, u32) {
+        let img = resize.apply(DynamicImage::ImageRgb8(RgbImage::new(width, height)));
+        (img.width(), img.height())
+    }
+
+    fn cover(width: u32, height: u32) -> Resize {
+        Resize {
+            width: Some(width),
+            height: Some(height),
+            fit: Fit::Cover,
+        }
+    }
+
+    #[test]
+    fn cover_fills_the_box() {
+        assert_eq!(resized(800, 600, cover(400, 400)), (400, 400));
+        assert_eq!(resized(300, 800, cover(200, 100)), (200, 100));
+    }
+
+    #[test]
+    fn cover_crops_instead_of_upscaling() {
+        assert_eq!(resized(800, 300, cover(400, 400)), (300, 300));
+        assert_eq!(resized(300, 800, cover(400, 200)), (300, 150));
+        assert_eq!(resized(200, 100, cover(400, 400)), (200, 100));
+    }
+
+    #[test]
+    fn detects_upload_extensions() {
+        for (format, extension) in [
+            (ImageFormat::Png, "png"),
+            (ImageFormat::Jpeg, "jpg"),
+            (ImageFormat::Gif, "gif"),
+            (ImageFormat::WebP, "webp"),
+        ] {
+            let mut data = std::io::Cursor::new(Vec::new());
+            DynamicImage::ImageRgb8(RgbImage::new(4, 4))
+                .write_to(&mut data, format)
+                .unwrap();
+            assert_eq!(Image::extension_for(data.get_ref()), Some(extension));
+        }
+
+        assert_eq!(Image::extension_for(b"\x89PNG"), None);
+        assert_eq!(
+            Image::extension_for(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            None
+        );
+        assert_eq!(Image::extension_for(&[0; 64]), None);
+    }
+
+    /// EXIF with the given ASCII fields, e.g. `(Tag::DateTimeOriginal, "2026:03:01 12:00:00")`
+    fn exif(fields: &[(Tag, &str)]) -> exif::Exif {
+        let fields: Vec<exif::Field> = fields
+            .iter()
+            .map(|(tag, value)| exif::Field {
+                tag: *tag,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![value.as_bytes().to_vec()]),
+            })
+            .collect();
+        let mut writer = exif::experimental::Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        Reader::new().read_raw(tiff.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn dates_images_from_the_first_available_source() {
+        use DateSource::*;
+
+        let path = std::env::temp_dir().join(format!("jorge-date-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let mtime = Utc.with_ymd_and_hms(2020, 5, 6, 7, 8, 9).unwrap();
+        file.set_modified(mtime.into()).unwrap();
+        let metadata = file.metadata().unwrap();
+
+        let utc = |y, mo, d, h, mi| Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap();
+        let original = (Tag::DateTimeOriginal, "2026:03:01 12:00:00");
+        let digitized = (Tag::DateTimeDigitized, "2026:03:02 12:00:00");
+        let helsinki = chrono_tz::Europe::Helsinki;
+        let all = [DateTimeOriginal, CreateDate, Modified];
+
+        // EXIF fields, date sources, timezone and the expected date
+        type Case<'a> = (
+            &'a [(Tag, &'a str)],
+            &'a [DateSource],
+            Tz,
+            Option<DateTime<Utc>>,
+        );
+        let cases: &[Case] = &[
+            // Naive EXIF dates are in the configured timezone
+            (&[original], &all, Tz::UTC, Some(utc(2026, 3, 1, 12, 0))),
+            (&[original], &all, helsinki, Some(utc(2026, 3, 1, 10, 0))),
+            // An offset tag wins over the timezone
+            (
+                &[original, (Tag::OffsetTimeOriginal, "+03:00")],
+                &all,
+                helsinki,
+                Some(utc(2026, 3, 1, 9, 0)),
+            ),
+            (
+                &[digitized, (Tag::OffsetTimeDigitized, "-05:00")],
+                &[CreateDate],
+                helsinki,
+                Some(utc(2026, 3, 2, 17, 0)),
+            ),
+            // Times repeated when DST ends take the first of them
+            (
+                &[(Tag::DateTimeOriginal, "2026:10:25 03:30:00")],
+                &all,
+                helsinki,
+                Some(utc(2026, 10, 25, 0, 30)),
+            ),
+            // Sources are tried in the configured order
+            (
+                &[original, digitized],
+                &all,
+                Tz::UTC,
+                Some(utc(2026, 3, 1, 12, 0)),
+            ),
+            (
+                &[original, digitized],
+                &[CreateDate, DateTimeOriginal],
+                Tz::UTC,
+                Some(utc(2026, 3, 2, 12, 0)),
+            ),
+            (&[digitized], &all, Tz::UTC, Some(utc(2026, 3, 2, 12, 0))),
+            (
+                &[original],
+                &[Modified, DateTimeOriginal],
+                Tz::UTC,
+                Some(mtime),
+            ),
+            // Missing and invalid EXIF dates fall back to the modification time
+            (&[], &all, Tz::UTC, Some(mtime)),
+            (
+                &[(Tag::DateTimeOriginal, "0000:00:00 00:00:00")],
+                &all,
+                Tz::UTC,
+                Some(mtime),
+            ),
+            (&[], &[DateTimeOriginal, CreateDate], Tz::UTC, None),
+        ];
+
+        for (fields, sources, timezone, expected) in cases {
+            let exif = (!fields.is_empty()).then(|| exif(fields));
+            let date = Image::image_date(exif.as_ref(), &metadata, sources, timezone).ok();
+            assert_eq!(
+                date, *expected,
+                "{:?} from {:?} in {}",
+                fields, sources, timezone
+            );
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn contain_never_upscales() {
+        let contain = Resize {
+            width: Some(400),
+            height: None,
+            fit: Fit::Contain,
+        };
+        assert_eq!(resized(800, 300, contain), (400, 150));
+        assert_eq!(resized(200, 100, contain), (200, 100));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::image_cache::captions::Caption;
+use crate::image_cache::image::{DateSource, ShotDetails};
+use crate::image_cache::storage::{read_json, write_json_atomic};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    path::PathBuf,
+};
+
+/// Metadata persisted for a single image between restarts
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexEntry {
+    pub path: PathBuf,
+    pub content_type: String,
+    pub image_age: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+    pub size: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// SHA-256 of the file contents, which is also the image ID
+    pub hash: String,
+    #[serde(default)]
+    pub details: ShotDetails,
+    #[serde(default)]
+    pub caption: Caption,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+impl IndexEntry {
+    /// Whether the file on disk still matches what was indexed
+    pub fn is_fresh(&self, metadata: &Metadata) -> bool {
+        let modified = match metadata.modified() {
+            Ok(modified) => DateTime::<Utc>::from(modified),
+            Err(_) => return false,
+        };
+
+        self.size == metadata.len() && self.modified == modified
+    }
+}
+
+/// Bumped whenever indexed images gain information that needs a rescan
+const INDEX_VERSION: u32 = 5;
+
+#[derive(Default, Deserialize, Serialize)]
+struct IndexFile {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    date_sources: Vec<DateSource>,
+    /// Timezone of EXIF dates that have no offset
+    #[serde(default)]
+    timezone: Option<Tz>,
+    images: Vec<IndexEntry>,
+}
+
+pub struct Index {
+    path: PathBuf,
+    date_sources: Vec<DateSource>,
+    timezone: Tz,
+    entries: HashMap<PathBuf, IndexEntry>,
+    dirty: bool,
+}
+
+impl Index {
+    /// Loads the index from disk, starting from scratch if it is missing or unreadable
+    /// or if its image dates were read from different sources or in another timezone.
+    pub fn load(path: &str, date_sources: &[DateSource], timezone: Tz) -> Self {
+        let path = PathBuf::from(path);
+        let index_file: IndexFile = read_json(&path, "Image index entries");
+
+        // Entries from older versions, other date sources or timezones have to be read again
+        let outdated = index_file.version != INDEX_VERSION
+            || index_file.date_sources != date_sources
+            || index_file.timezone != Some(timezone);
+        let index_file = if outdated && !index_file.images.is_empty() {
+            info!("Image index is outdated, rebuilding image index");
+            IndexFile::default()
+        } else {
+            index_file
+        };
+
+        debug!(
+            "Loaded {} entries from image index",
+            index_file.images.len()
+        );
+        Self {
+            path,
+            date_sources: date_sources.to_vec(),
+            timezone,
+            entries: index_file
+                .images
+                .into_iter()
+                .map(|entry| (entry.path.clone(), entry))
+                .collect(),
+            dirty: false,
+        }
+    }
+
+    pub fn get(&self, path: &PathBuf) -> Option<&IndexEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn insert(&mut self, entry: IndexEntry) {
+        self.entries.insert(entry.path.clone(), entry);
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, path: &PathBuf) -> Option<IndexEntry> {
+        let entry = self.entries.remove(path);
+        if entry.is_some() {
+            self.dirty = true;
+        }
+        entry
+    }
+
+    /// Drops every entry whose path is not in `paths`
+    pub fn retain(&mut self, paths: &HashSet<PathBuf>) {
+        let before = self.entries.len();
+        self.entries.retain(|path, _| paths.contains(path));
+        if self.entries.len() != before {
+            debug!(
+                "Pruned {} stale entries from image index",
+                before - self.entries.len()
+            );
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the index to disk if it has changed since the last save.
+    pub fn save(&mut self) -> Result<(), anyhow::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let index_file = IndexFile {
+            version: INDEX_VERSION,
+            date_sources: self.date_sources.clone(),
+            timezone: Some(self.timezone),
+            images: self.entries.values().cloned().collect(),
+        };
+
+        write_json_atomic(&self.path, &index_file)?;
+
+        self.dirty = false;
+        debug!("Saved {} entries to image index", self.entries.len());
+        Ok(())
+    }
+}
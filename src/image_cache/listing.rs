@@ -0,0 +1,229 @@
+use crate::image_cache::image::Image;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+
+/// Page size of GET /images when the client does not pick one
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Order of GET /images
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+    /// Shuffled by a seed, so every page of a listing uses the same order
+    Random,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Newest => "newest",
+            SortOrder::Oldest => "oldest",
+            SortOrder::Random => "random",
+        }
+    }
+}
+
+/// Position after the last image of a page, images are ordered by `key` and then by ID.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    key: i128,
+    id: String,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.key, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(cursor: &str) -> Result<Self, Self::Err> {
+        let (key, id) = cursor
+            .split_once('.')
+            .filter(|(_, id)| !id.is_empty())
+            .ok_or_else(|| anyhow!("Invalid cursor"))?;
+
+        Ok(Self {
+            key: key.parse()?,
+            id: id.to_owned(),
+        })
+    }
+}
+
+/// The page size a client asked for, kept between 1 and `MAX_PAGE_SIZE`
+pub fn page_size(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+/// Which images to list and how
+pub struct Listing {
+    pub sort: SortOrder,
+    pub seed: u64,
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only list images with one of these tags, unless empty
+    pub tags: Vec<String>,
+    pub cursor: Option<Cursor>,
+    pub limit: usize,
+}
+
+impl Listing {
+    fn sort_key(&self, id: &str, image: &Image) -> i128 {
+        let nanos = image.image_age.timestamp_nanos_opt().unwrap_or_default() as i128;
+
+        match self.sort {
+            SortOrder::Newest => -nanos,
+            SortOrder::Oldest => nanos,
+            SortOrder::Random => {
+                let digest = Sha256::new()
+                    .chain_update(self.seed.to_be_bytes())
+                    .chain_update(id)
+                    .finalize();
+                let mut key = [0; 8];
+                key.copy_from_slice(&digest[..8]);
+                u64::from_be_bytes(key) as i128
+            }
+        }
+    }
+
+    /// Returns one page of `images` and the cursor of the next one, if there is one.
+    /// Only the images on the page are cloned.
+    pub fn page<'a>(
+        &self,
+        images: impl Iterator<Item = (&'a String, &'a Image)>,
+    ) -> (Vec<(String, Image)>, Option<Cursor>) {
+        let after = self
+            .cursor
+            .as_ref()
+            .map(|cursor| (cursor.key, cursor.id.as_str()));
+        let mut matching: Vec<((i128, &str), &Image)> = images
+            .filter(|(_, img)| self.from.is_none_or(|from| img.image_age >= from))
+            .filter(|(_, img)| self.until.is_none_or(|until| img.image_age < until))
+            .filter(|(_, img)| self.tags.is_empty() || img.has_any_tag(&self.tags))
+            .map(|(id, img)| ((self.sort_key(id, img), id.as_str()), img))
+            .filter(|(position, _)| after.is_none_or(|after| *position > after))
+            .collect();
+        matching.sort_by_key(|(position, _)| *position);
+
+        let next = if matching.len() > self.limit {
+            matching
+                .get(self.limit.saturating_sub(1))
+                .map(|((key, id), _)| Cursor {
+                    key: *key,
+                    id: (*id).to_owned(),
+                })
+        } else {
+            None
+        };
+
+        let page = matching
+            .into_iter()
+            .take(self.limit)
+            .map(|((_, id), img)| (id.to_owned(), img.to_owned()))
+            .collect();
+        (page, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::collections::BTreeMap;
+
+    fn images(days: &[(&str, u32)]) -> BTreeMap<String, Image> {
+        days.iter()
+            .map(|(id, day)| {
+                let date = NaiveDate::from_ymd_opt(2026, 1, *day).unwrap();
+                (id.to_string(), Image::test_dated(id, date))
+            })
+            .collect()
+    }
+
+    fn listing(sort: SortOrder, cursor: Option<Cursor>, limit: usize) -> Listing {
+        Listing {
+            sort,
+            seed: 0,
+            from: None,
+            until: None,
+            tags: Vec::new(),
+            cursor,
+            limit,
+        }
+    }
+
+    fn ids(page: &[(String, Image)]) -> Vec<&str> {
+        page.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    #[test]
+    fn pages_continue_after_their_cursor() {
+        let images = images(&[("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 5)]);
+
+        let (page, next) = listing(SortOrder::Newest, None, 2).page(images.iter());
+        assert_eq!(ids(&page), ["e", "d"]);
+
+        let (page, next) = listing(SortOrder::Newest, next, 2).page(images.iter());
+        assert_eq!(ids(&page), ["c", "b"]);
+
+        let (page, next) = listing(SortOrder::Newest, next, 2).page(images.iter());
+        assert_eq!(ids(&page), ["a"]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn full_last_page_has_no_next_cursor() {
+        let images = images(&[("a", 1), ("b", 2)]);
+
+        let (page, next) = listing(SortOrder::Oldest, None, 2).page(images.iter());
+        assert_eq!(ids(&page), ["a", "b"]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn same_date_is_ordered_by_id() {
+        let images = images(&[("c", 1), ("a", 1), ("b", 1)]);
+
+        for sort in [SortOrder::Newest, SortOrder::Oldest] {
+            let (page, next) = listing(sort, None, 2).page(images.iter());
+            assert_eq!(ids(&page), ["a", "b"]);
+
+            let (page, _) = listing(sort, next, 2).page(images.iter());
+            assert_eq!(ids(&page), ["c"]);
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor {
+            key: -1_767_268_800_000_000_000,
+            id: "abc".to_owned(),
+        };
+        assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        for cursor in ["", "abc", "12", "x.abc", "1."] {
+            assert!(cursor.parse::<Cursor>().is_err(), "{}", cursor);
+        }
+    }
+
+    #[test]
+    fn clamps_page_size() {
+        assert_eq!(page_size(None), DEFAULT_PAGE_SIZE);
+        assert_eq!(page_size(Some(0)), 1);
+        assert_eq!(page_size(Some(20)), 20);
+        assert_eq!(page_size(Some(MAX_PAGE_SIZE + 1)), MAX_PAGE_SIZE);
+    }
+}
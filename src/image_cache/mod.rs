@@ -1,2 +1,13 @@
 pub mod cache;
+pub mod captions;
+pub mod daily;
+pub mod derivatives;
+pub mod encoder;
+pub mod hidden;
 pub mod image;
+pub mod index;
+pub mod listing;
+pub mod privacy;
+pub mod segments;
+pub mod storage;
+pub mod tags;
@@ -0,0 +1,515 @@
+use crate::image_cache::segments::{Container, SegmentWalker, find};
+
+use anyhow::anyhow;
+use exif::{Context, Reader, Tag};
+use log::{error, info};
+use std::{collections::HashSet, io::Cursor, path::PathBuf};
+use walkdir::WalkDir;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+/// GIF application extensions that only affect how the image is shown
+const GIF_KEPT_APPLICATIONS: [&[u8]; 3] = [b"NETSCAPE2.0", b"ANIMEXTS1.0", b"ICCRGBG1012"];
+
+/// How the metadata of an original is removed before it is served
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stripping {
+    /// The format has nowhere to keep identifying metadata
+    Unneeded,
+    /// `strip_metadata` rewrites the file without it
+    Rewrite,
+    /// The format is too involved to rewrite, the image is re-encoded instead
+    Reencode,
+}
+
+/// Removes location and other identifying metadata from JPEG, PNG, WebP and GIF files.
+/// Orientation and color profiles are kept so the image still renders the same,
+/// other formats are refused rather than passed through with their metadata.
+pub fn strip_metadata(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let exif = minimal_exif(data);
+
+    match Container::detect(data) {
+        Some(Container::Jpeg) => strip_jpeg(data, exif),
+        Some(Container::Png) => strip_png(data, exif),
+        Some(Container::WebP) => strip_webp(data, exif),
+        None if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") => strip_gif(data),
+        None => Err(anyhow!("Cannot strip metadata from this image format")),
+    }
+}
+
+/// Whether a file carries GPS coordinates in its EXIF or XMP metadata
+pub fn contains_gps(data: &[u8]) -> bool {
+    let exif_gps = Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(data))
+        .map(|exif| {
+            exif.fields().any(|field| {
+                field.tag.context() == Context::Gps || field.tag == Tag::GPSInfoIFDPointer
+            })
+        })
+        .unwrap_or(false);
+
+    exif_gps || contains(data, b"GPSLatitude") || contains(data, b"GPSLongitude")
+}
+
+/// Lists every image under `directories` that still contains GPS data, returns how many did
+pub fn audit(directories: &[String]) -> usize {
+    // Upload directories may live inside image directories, every file is checked once
+    let mut seen = HashSet::new();
+    let files = directories
+        .iter()
+        .flat_map(WalkDir::new)
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .filter(|path| seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())));
+
+    let mut checked = 0;
+    let mut found: Vec<PathBuf> = Vec::new();
+    for path in files {
+        match std::fs::read(&path) {
+            Ok(data) => {
+                checked += 1;
+                if contains_gps(&data) {
+                    println!("{}", path.display());
+                    found.push(path);
+                }
+            }
+            Err(e) => error!("Cannot read {:#?}: {}", path, e),
+        }
+    }
+
+    info!(
+        "Checked {} files, {} still contain GPS data",
+        checked,
+        found.len()
+    );
+    found.len()
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    find(data, needle).is_some()
+}
+
+/// A big endian TIFF structure holding nothing but the orientation of `data`
+fn minimal_exif(data: &[u8]) -> Option<Vec<u8>> {
+    let orientation = Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(data))
+        .ok()?
+        .get_field(Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)?;
+
+    let mut tiff = b"MM\0\x2A\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&(orientation as u16).to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    Some(tiff)
+}
+
+fn strip_jpeg(data: &[u8], mut exif: Option<Vec<u8>>) -> Result<Vec<u8>, anyhow::Error> {
+    let mut out = data[..Container::Jpeg.start()].to_vec();
+    let mut segments = SegmentWalker::new(Cursor::new(data), Container::Jpeg)?;
+
+    while let Some(segment) = segments.next_segment()? {
+        let mut payload = Vec::new();
+        segments.read_data(&mut payload)?;
+
+        let keep = match segment.marker() {
+            0xE1 => {
+                // Swap the first EXIF block for one that only holds the orientation
+                if payload.starts_with(EXIF_HEADER)
+                    && let Some(tiff) = exif.take()
+                {
+                    let payload_length = EXIF_HEADER.len() + tiff.len() + 2;
+                    out.extend_from_slice(&[0xFF, 0xE1]);
+                    out.extend_from_slice(&(payload_length as u16).to_be_bytes());
+                    out.extend_from_slice(EXIF_HEADER);
+                    out.extend_from_slice(&tiff);
+                }
+                false
+            }
+            0xE2 => payload.starts_with(ICC_HEADER),
+            // JFIF and Adobe color transform segments
+            0xE0 | 0xEE => true,
+            // Vendor segments, IPTC and comments
+            0xE3..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(&segment.header);
+            out.extend_from_slice(&payload);
+        }
+    }
+
+    // The image data runs until the end of image marker, anything after it is dropped
+    // since cameras append previews there that carry their own metadata
+    let start = segments.position() as usize;
+    let end = find(&data[start..], &[0xFF, 0xD9]).map_or(data.len(), |offset| start + offset + 2);
+    out.extend_from_slice(&data[start..end]);
+    Ok(out)
+}
+
+fn strip_png(data: &[u8], mut exif: Option<Vec<u8>>) -> Result<Vec<u8>, anyhow::Error> {
+    let mut out = data[..Container::Png.start()].to_vec();
+    let mut chunks = SegmentWalker::new(Cursor::new(data), Container::Png)?;
+
+    while let Some(chunk) = chunks.next_segment()? {
+        match chunk.kind() {
+            b"eXIf" => {
+                if let Some(tiff) = exif.take() {
+                    write_png_chunk(&mut out, b"eXIf", &tiff);
+                }
+            }
+            // Text chunks hold XMP, comments and anything else a tool felt like adding
+            b"tEXt" | b"zTXt" | b"iTXt" => {}
+            _ => {
+                out.extend_from_slice(&chunk.header);
+                chunks.read_data(&mut out)?;
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+fn strip_webp(data: &[u8], mut exif: Option<Vec<u8>>) -> Result<Vec<u8>, anyhow::Error> {
+    // VP8X flags for the presence of EXIF and XMP chunks
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+
+    let mut out = data[..Container::WebP.start()].to_vec();
+    let mut wrote_exif = false;
+    let mut flags_at = None;
+    let mut chunks = SegmentWalker::new(Cursor::new(data), Container::WebP)?;
+
+    while let Some(chunk) = chunks.next_segment()? {
+        match chunk.kind() {
+            b"EXIF" => {
+                if let Some(tiff) = exif.take() {
+                    out.extend_from_slice(b"EXIF");
+                    out.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+                    out.extend_from_slice(&tiff);
+                    if tiff.len() % 2 == 1 {
+                        out.push(0);
+                    }
+                    wrote_exif = true;
+                }
+            }
+            b"XMP " => {}
+            fourcc => {
+                if fourcc == b"VP8X" {
+                    flags_at = Some(out.len() + 8);
+                }
+                out.extend_from_slice(&chunk.header);
+                chunks.read_data(&mut out)?;
+            }
+        }
+    }
+
+    if let Some(flags) = flags_at.and_then(|at| out.get_mut(at)) {
+        *flags &= !XMP_FLAG;
+        if !wrote_exif {
+            *flags &= !EXIF_FLAG;
+        }
+    }
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+/// Drops comments and application extensions other than looping and color profiles
+fn strip_gif(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    // Header and logical screen descriptor, followed by the optional global color table
+    let flags = *data
+        .get(10)
+        .ok_or_else(|| anyhow!("Truncated GIF header"))?;
+    let mut pos = 13 + gif_color_table_size(flags);
+    let mut out = data
+        .get(..pos)
+        .ok_or_else(|| anyhow!("Truncated GIF color table"))?
+        .to_vec();
+
+    loop {
+        match data.get(pos) {
+            Some(0x3B) => {
+                out.push(0x3B);
+                return Ok(out);
+            }
+            Some(0x2C) => {
+                let flags = *data
+                    .get(pos + 9)
+                    .ok_or_else(|| anyhow!("Truncated GIF image at {}", pos))?;
+                // Image descriptor, local color table and the LZW code size
+                let data_start = pos + 10 + gif_color_table_size(flags) + 1;
+                let end = gif_sub_blocks_end(data, data_start)?;
+                out.extend_from_slice(&data[pos..end]);
+                pos = end;
+            }
+            Some(0x21) => {
+                let label = *data
+                    .get(pos + 1)
+                    .ok_or_else(|| anyhow!("Truncated GIF extension at {}", pos))?;
+                let end = gif_sub_blocks_end(data, pos + 2)?;
+                let keep = match label {
+                    // Graphic control and plain text extensions
+                    0xF9 | 0x01 => true,
+                    0xFF => {
+                        data.get(pos + 2) == Some(&11)
+                            && data
+                                .get(pos + 3..pos + 14)
+                                .is_some_and(|id| GIF_KEPT_APPLICATIONS.contains(&id))
+                    }
+                    // Comments and anything unknown
+                    _ => false,
+                };
+                if keep {
+                    out.extend_from_slice(&data[pos..end]);
+                }
+                pos = end;
+            }
+            _ => return Err(anyhow!("Malformed GIF block at {}", pos)),
+        }
+    }
+}
+
+fn gif_color_table_size(flags: u8) -> usize {
+    match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 0x07) + 1),
+    }
+}
+
+/// Finds the end of the data sub-blocks starting at `pos`, past their zero length terminator
+fn gif_sub_blocks_end(data: &[u8], mut pos: usize) -> Result<usize, anyhow::Error> {
+    loop {
+        let length = *data
+            .get(pos)
+            .ok_or_else(|| anyhow!("Truncated GIF data at {}", pos))? as usize;
+        pos += 1 + length;
+        if length == 0 {
+            return Ok(pos);
+        }
+        if pos > data.len() {
+            return Err(anyhow!("Truncated GIF data at {}", pos));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+    const ICC_PROFILE: &[u8] = b"not really a color profile";
+
+    /// A big endian TIFF structure with an orientation of 6 and a GPS latitude
+    fn gps_exif() -> Vec<u8> {
+        let mut tiff = b"MM\0\x2A\0\0\0\x08".to_vec();
+        // IFD0 with the orientation and a pointer to the GPS IFD right after it
+        tiff.extend_from_slice(&2u16.to_be_bytes());
+        tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        tiff.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 38]);
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+        // GPS IFD with GPSLatitudeRef = "N"
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&[0, 1, 0, 2, 0, 0, 0, 2, b'N', 0, 0, 0]);
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+        tiff
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 4, Rgb([200, 120, 40])));
+        let mut data = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn gps_jpeg() -> Vec<u8> {
+        let data = encode(ImageFormat::Jpeg);
+        let mut exif = EXIF_HEADER.to_vec();
+        exif.extend_from_slice(&gps_exif());
+        let mut icc = ICC_HEADER.to_vec();
+        icc.extend_from_slice(&[1, 1]);
+        icc.extend_from_slice(ICC_PROFILE);
+
+        let mut out = data[0..2].to_vec();
+        out.extend(jpeg_segment(0xE1, &exif));
+        out.extend(jpeg_segment(0xE2, &icc));
+        out.extend(jpeg_segment(0xFE, b"GPSLatitude 60.17"));
+        out.extend_from_slice(&data[2..]);
+        out
+    }
+
+    /// Wraps `data` in a zlib stream made of a single stored block
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let (mut a, mut b) = (1u32, 0u32);
+        for byte in data {
+            a = (a + *byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+
+        let mut out = vec![0x78, 0x01, 0x01];
+        out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+        out.extend_from_slice(data);
+        out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        out
+    }
+
+    fn gps_png() -> Vec<u8> {
+        let data = encode(ImageFormat::Png);
+        // The signature and IHDR come first, metadata goes right after them
+        let ihdr_end = Container::Png.start() + 12 + 13;
+        let mut iccp = b"icc\0\0".to_vec();
+        iccp.extend(zlib_stored(ICC_PROFILE));
+
+        let mut out = data[..ihdr_end].to_vec();
+        write_png_chunk(&mut out, b"iCCP", &iccp);
+        write_png_chunk(&mut out, b"eXIf", &gps_exif());
+        write_png_chunk(&mut out, b"tEXt", b"Comment\0GPSLongitude 24.94");
+        out.extend_from_slice(&data[ihdr_end..]);
+        out
+    }
+
+    fn webp_chunk(fourcc: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = fourcc.to_vec();
+        chunk.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn gps_webp() -> Vec<u8> {
+        let data = encode(ImageFormat::WebP);
+        // ICC, EXIF and XMP flags, then the canvas size minus one
+        let mut vp8x = vec![0x20 | 0x08 | 0x04, 0, 0, 0];
+        vp8x.extend_from_slice(&7u32.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&3u32.to_le_bytes()[..3]);
+
+        let mut out = b"RIFF\0\0\0\0WEBP".to_vec();
+        out.extend(webp_chunk(b"VP8X", &vp8x));
+        out.extend(webp_chunk(b"ICCP", ICC_PROFILE));
+        out.extend_from_slice(&data[12..]);
+        out.extend(webp_chunk(b"EXIF", &gps_exif()));
+        out.extend(webp_chunk(
+            b"XMP ",
+            b"<x:xmpmeta><exif:GPSLatitude>60,10N</exif:GPSLatitude></x:xmpmeta>",
+        ));
+        let riff_size = (out.len() - 8) as u32;
+        out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        out
+    }
+
+    fn orientation(data: &[u8]) -> Option<u32> {
+        Reader::new()
+            .read_from_container(&mut std::io::Cursor::new(data))
+            .ok()?
+            .get_field(Tag::Orientation, exif::In::PRIMARY)?
+            .value
+            .get_uint(0)
+    }
+
+    fn assert_stripped(original: &[u8]) {
+        assert!(contains_gps(original));
+        assert!(image::load_from_memory(original).is_ok());
+
+        let stripped = strip_metadata(original).unwrap();
+        assert!(!contains_gps(&stripped));
+        assert_eq!(orientation(&stripped), Some(6));
+        assert!(contains(&stripped, ICC_PROFILE) || contains(&stripped, &zlib_stored(ICC_PROFILE)));
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn strips_gps_from_jpeg() {
+        assert_stripped(&gps_jpeg());
+    }
+
+    #[test]
+    fn strips_gps_from_png() {
+        assert_stripped(&gps_png());
+    }
+
+    #[test]
+    fn strips_gps_from_webp() {
+        let stripped = strip_metadata(&gps_webp()).unwrap();
+        assert!(!contains(&stripped, b"XMP "));
+        // The XMP flag goes with the XMP chunk, EXIF and ICC stay
+        assert_eq!(stripped[20] & (0x20 | 0x08 | 0x04), 0x20 | 0x08);
+        assert_stripped(&gps_webp());
+    }
+
+    #[test]
+    fn strips_comments_from_gif() {
+        let data = encode(ImageFormat::Gif);
+        let mut original = data[..data.len() - 1].to_vec();
+        original.extend_from_slice(&[0x21, 0xFE, 17]);
+        original.extend_from_slice(b"GPSLatitude 60.17");
+        original.extend_from_slice(&[0, 0x3B]);
+        assert!(contains_gps(&original));
+
+        let stripped = strip_metadata(&original).unwrap();
+        assert!(!contains_gps(&stripped));
+        assert_eq!(stripped, data);
+    }
+
+    #[test]
+    fn refuses_formats_it_cannot_strip() {
+        assert!(strip_metadata(b"II*\0\x08\0\0\0\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn rejects_short_jpeg_segment_lengths() {
+        for length in [0u8, 1] {
+            let data = [0xFF, 0xD8, 0xFF, 0xE1, 0, length, 0xFF, 0xD9];
+            assert!(strip_metadata(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let jpeg = gps_jpeg();
+        assert!(strip_metadata(&jpeg[..30]).is_err());
+        assert!(strip_metadata(&[0xFF, 0xD8, 0x00, 0x00]).is_err());
+
+        let png = gps_png();
+        assert!(strip_metadata(&png[..Container::Png.start() + 20]).is_err());
+
+        let webp = gps_webp();
+        assert!(strip_metadata(&webp[..16]).is_err());
+    }
+
+    #[test]
+    fn never_panics_on_partial_files() {
+        for data in [gps_jpeg(), gps_png(), gps_webp(), encode(ImageFormat::Gif)] {
+            for end in 0..data.len() {
+                let _ = strip_metadata(&data[..end]);
+            }
+        }
+    }
+}
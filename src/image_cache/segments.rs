@@ -0,0 +1,253 @@
+use anyhow::anyhow;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Formats that are made of segments or chunks, which is where their metadata is kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl Container {
+    /// The container of a file that starts with `data`
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0xFF, 0xD8]) {
+            Some(Container::Jpeg)
+        } else if data.starts_with(PNG_SIGNATURE) {
+            Some(Container::Png)
+        } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(Container::WebP)
+        } else {
+            None
+        }
+    }
+
+    /// Length of the signature in front of the first segment
+    pub fn start(self) -> usize {
+        match self {
+            Container::Jpeg => 2,
+            Container::Png => PNG_SIGNATURE.len(),
+            Container::WebP => 12,
+        }
+    }
+}
+
+/// A JPEG segment, or a PNG or WebP chunk
+pub struct Segment {
+    container: Container,
+    /// Marker, length and type as they appear in the file
+    pub header: Vec<u8>,
+}
+
+impl Segment {
+    /// Marker of a JPEG segment
+    pub fn marker(&self) -> u8 {
+        self.header[1]
+    }
+
+    /// Type of a PNG or WebP chunk
+    pub fn kind(&self) -> &[u8] {
+        match self.container {
+            Container::Jpeg => &self.header[..2],
+            Container::Png => &self.header[4..8],
+            Container::WebP => &self.header[0..4],
+        }
+    }
+
+    /// Whether the segment holds pixels rather than metadata
+    pub fn is_image_data(&self) -> bool {
+        match self.container {
+            // The walk ends where JPEG image data starts
+            Container::Jpeg => false,
+            Container::Png => self.kind() == b"IDAT",
+            Container::WebP => matches!(self.kind(), b"VP8 " | b"VP8L" | b"ALPH" | b"ANMF"),
+        }
+    }
+}
+
+/// Walks the segments of a file one at a time. The data of a segment is only read when asked
+/// for, so image data can be skipped without reading it.
+pub struct SegmentWalker<R> {
+    reader: R,
+    container: Container,
+    /// Offset of the next unread byte
+    position: u64,
+    length: u64,
+    /// Data of the current segment that has been neither read nor skipped
+    remaining: u64,
+    /// WebP chunks are padded to an even length
+    padding: u64,
+}
+
+impl<R: Read + Seek> SegmentWalker<R> {
+    pub fn new(mut reader: R, container: Container) -> Result<Self, anyhow::Error> {
+        let length = reader.seek(SeekFrom::End(0))?;
+        let position = reader.seek(SeekFrom::Start(container.start() as u64))?;
+
+        Ok(Self {
+            reader,
+            container,
+            position,
+            length,
+            remaining: 0,
+            padding: 0,
+        })
+    }
+
+    /// Offset of the next segment, or of the JPEG image data once the walk is done
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The next segment, `None` at the start of JPEG image data or the end of a PNG or WebP
+    /// file. Its data is skipped unless it is read with `read_data` first.
+    pub fn next_segment(&mut self) -> Result<Option<Segment>, anyhow::Error> {
+        self.skip_data()?;
+
+        match self.container {
+            Container::Jpeg => self.next_jpeg_segment(),
+            Container::Png | Container::WebP => self.next_chunk(),
+        }
+    }
+
+    /// Appends the data of the current segment to `out`, including the padding of WebP chunks
+    pub fn read_data(&mut self, out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        let read = self.reader.by_ref().take(self.remaining).read_to_end(out)? as u64;
+        self.position += read;
+        if read < self.remaining {
+            return Err(anyhow!("Truncated segment at {}", self.position));
+        }
+        self.remaining = 0;
+
+        // The last chunk may end without its padding
+        if self.padding > 0 {
+            let mut padding = [0u8];
+            self.position += self.reader.read(&mut padding)? as u64;
+            out.push(padding[0]);
+            self.padding = 0;
+        }
+        Ok(())
+    }
+
+    fn skip_data(&mut self) -> Result<(), anyhow::Error> {
+        if self.position + self.remaining > self.length {
+            return Err(anyhow!("Truncated segment at {}", self.position));
+        }
+
+        let skip = (self.remaining + self.padding).min(self.length - self.position);
+        self.reader.seek_relative(skip as i64)?;
+        self.position += skip;
+        self.remaining = 0;
+        self.padding = 0;
+        Ok(())
+    }
+
+    fn next_jpeg_segment(&mut self) -> Result<Option<Segment>, anyhow::Error> {
+        loop {
+            let mut header = vec![0u8; 2];
+            if !self.read_header(&mut header)? {
+                return Err(anyhow!("Malformed JPEG marker at {}", self.position));
+            }
+            let marker = match header[..] {
+                // Fill bytes, the second one may start the marker
+                [0xFF, 0xFF] => {
+                    self.rewind(1)?;
+                    continue;
+                }
+                [0xFF, marker] => marker,
+                _ => return Err(anyhow!("Malformed JPEG marker at {}", self.position - 2)),
+            };
+
+            // Metadata segments all come before the image data
+            if marker == 0xDA || marker == 0xD9 {
+                self.rewind(2)?;
+                return Ok(None);
+            }
+
+            // Standalone markers carry no length
+            if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+                return Ok(Some(self.segment(header, 0)));
+            }
+
+            header.resize(4, 0);
+            if !self.read_header(&mut header[2..])? {
+                return Err(anyhow!("Truncated JPEG segment at {}", self.position));
+            }
+            // The length counts its own two bytes
+            let length = u16::from_be_bytes([header[2], header[3]]) as u64;
+            if length < 2 {
+                return Err(anyhow!(
+                    "Malformed JPEG segment length {} at {}",
+                    length,
+                    self.position - 4
+                ));
+            }
+            return Ok(Some(self.segment(header, length - 2)));
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Segment>, anyhow::Error> {
+        let mut header = vec![0u8; 8];
+        if !self.read_header(&mut header)? {
+            return Ok(None);
+        }
+
+        Ok(Some(match self.container {
+            Container::Png => {
+                let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                // Chunk data and its CRC
+                self.segment(header, length as u64 + 4)
+            }
+            _ => {
+                let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+                self.padding = length as u64 & 1;
+                self.segment(header, length as u64)
+            }
+        }))
+    }
+
+    /// A segment whose header was just read, `length` bytes of data follow it
+    fn segment(&mut self, header: Vec<u8>, length: u64) -> Segment {
+        self.remaining = length;
+        Segment {
+            container: self.container,
+            header,
+        }
+    }
+
+    /// Fills `header`, returns false if the file ended right before it
+    fn read_header(&mut self, header: &mut [u8]) -> Result<bool, anyhow::Error> {
+        let mut read = 0;
+        while read < header.len() {
+            match self.reader.read(&mut header[read..]) {
+                Ok(0) => break,
+                Ok(bytes) => read += bytes,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.position += read as u64;
+
+        match read {
+            0 => Ok(false),
+            read if read == header.len() => Ok(true),
+            _ => Err(anyhow!("Truncated segment at {}", self.position)),
+        }
+    }
+
+    fn rewind(&mut self, bytes: u64) -> Result<(), anyhow::Error> {
+        self.reader.seek_relative(-(bytes as i64))?;
+        self.position -= bytes;
+        Ok(())
+    }
+}
+
+/// Offset of the first `needle` in `haystack`
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
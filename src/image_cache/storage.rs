@@ -0,0 +1,72 @@
+use log::warn;
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    io::ErrorKind,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Numbers temporary files so no two writers share one
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the JSON file at `path`, a missing file is empty. Unreadable or corrupt files are
+/// treated as empty too but warned about, since they are overwritten on the next change.
+pub fn read_json<T: DeserializeOwned + Default>(path: &Path, what: &str) -> T {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return T::default(),
+        Err(e) => {
+            warn!(
+                "Unable to read {} from {:#?}, starting empty: {}",
+                what, path, e
+            );
+            return T::default();
+        }
+    };
+
+    serde_json::from_slice(&data).unwrap_or_else(|e| {
+        warn!("{} in {:#?} are corrupt, starting empty: {}", what, path, e);
+        T::default()
+    })
+}
+
+/// Writes `value` as JSON next to `path` first and renames it into place,
+/// so a crash never leaves a half written file behind.
+pub fn write_json_atomic<T: Serialize>(path: &Path, value: &T) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(value)?)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Writes `data` to a hidden temporary file next to `path` and renames it into place, so
+/// readers and directory watchers only ever see the complete file
+pub fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, tmp_suffix()));
+    let result = std::fs::write(&tmp_path, data).and_then(|_| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Whether `path` is a temporary file of `write_atomic` that has not been renamed yet
+pub fn is_temporary(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(".tmp"))
+}
+
+/// Process ID and a running number, unique for every temporary file of this process
+pub fn tmp_suffix() -> String {
+    format!(
+        "{}-{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
@@ -0,0 +1,254 @@
+use crate::image_cache::captions::{xmp_list, xmp_packet};
+use crate::image_cache::segments::{Container, SegmentWalker};
+
+use std::{collections::BTreeSet, io::Cursor, path::Path};
+
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+/// Photoshop image resource holding IPTC-IIM records
+const IPTC_RESOURCE: u16 = 0x0404;
+/// IPTC-IIM application record 2, dataset 25
+const IPTC_KEYWORDS: (u8, u8) = (2, 25);
+
+/// Lowercases, trims and deduplicates tags so "Vet Visit " and "vet visit" match
+pub fn normalize<I, S>(tags: I) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    tags.into_iter()
+        .map(|tag| tag.as_ref().trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
+
+/// Keywords from the XMP subject and the IPTC keywords embedded in an image
+pub fn from_embedded(data: &[u8]) -> Vec<String> {
+    let xmp = xmp_packet(data)
+        .map(|xmp| xmp_list(xmp, "dc:subject"))
+        .unwrap_or_default();
+
+    normalize(xmp.into_iter().chain(iptc_keywords(data)))
+}
+
+/// Names of the subdirectories between `directory` and the image at `path`
+pub fn from_directory(directory: &Path, path: &Path) -> Vec<String> {
+    let Some(relative) = path
+        .parent()
+        .and_then(|parent| parent.strip_prefix(directory).ok())
+    else {
+        return Vec::new();
+    };
+
+    normalize(
+        relative
+            .iter()
+            .map(|component| component.to_string_lossy().into_owned()),
+    )
+}
+
+/// Keywords from the IPTC records in the Photoshop segment of a JPEG
+fn iptc_keywords(data: &[u8]) -> Vec<String> {
+    if Container::detect(data) != Some(Container::Jpeg) {
+        return Vec::new();
+    }
+    let Ok(mut segments) = SegmentWalker::new(Cursor::new(data), Container::Jpeg) else {
+        return Vec::new();
+    };
+
+    while let Ok(Some(segment)) = segments.next_segment() {
+        if segment.marker() != 0xED {
+            continue;
+        }
+        let mut payload = Vec::new();
+        if segments.read_data(&mut payload).is_err() {
+            break;
+        }
+        if let Some(resources) = payload.strip_prefix(PHOTOSHOP_HEADER) {
+            return iptc_records(photoshop_resource(resources, IPTC_RESOURCE).unwrap_or(&[]));
+        }
+    }
+
+    Vec::new()
+}
+
+/// Data of the first Photoshop image resource with the given ID
+fn photoshop_resource(mut resources: &[u8], id: u16) -> Option<&[u8]> {
+    while resources.starts_with(b"8BIM") {
+        let resource_id = u16::from_be_bytes([*resources.get(4)?, *resources.get(5)?]);
+
+        // Pascal string name, padded to an even length including its length byte
+        let name_length = *resources.get(6)? as usize;
+        let name_end = 6 + (name_length + 2) / 2 * 2;
+
+        let size = resources.get(name_end..name_end + 4)?;
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let data = resources.get(name_end + 4..name_end + 4 + size)?;
+        if resource_id == id {
+            return Some(data);
+        }
+
+        let next = name_end + 4 + size + (size & 1);
+        resources = resources.get(next..)?;
+    }
+
+    None
+}
+
+fn iptc_records(mut records: &[u8]) -> Vec<String> {
+    let mut keywords = Vec::new();
+
+    while let [0x1C, record, dataset, high, low, rest @ ..] = records {
+        // Extended lengths are only used for large binary records
+        if high & 0x80 != 0 {
+            break;
+        }
+
+        let length = u16::from_be_bytes([*high, *low]) as usize;
+        let Some(value) = rest.get(..length) else {
+            break;
+        };
+        if (*record, *dataset) == IPTC_KEYWORDS {
+            keywords.push(String::from_utf8_lossy(value).into_owned());
+        }
+        records = &rest[length..];
+    }
+
+    keywords
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A Photoshop image resource with its name and data padded to even lengths
+    fn resource(id: u16, name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut resource = b"8BIM".to_vec();
+        resource.extend_from_slice(&id.to_be_bytes());
+        resource.push(name.len() as u8);
+        resource.extend_from_slice(name);
+        if name.len().is_multiple_of(2) {
+            resource.push(0);
+        }
+        resource.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        resource.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            resource.push(0);
+        }
+        resource
+    }
+
+    fn record(record: u8, dataset: u8, value: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x1C, record, dataset];
+        out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        out.extend_from_slice(value);
+        out
+    }
+
+    /// A JPEG whose only metadata is a Photoshop segment with `resources`
+    fn photoshop_jpeg(resources: &[u8]) -> Vec<u8> {
+        let mut payload = PHOTOSHOP_HEADER.to_vec();
+        payload.extend_from_slice(resources);
+
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xED];
+        data.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn reads_iptc_keywords() {
+        let records = [
+            record(1, 90, b"\x1B%G"),
+            record(2, 25, b"Sofa "),
+            record(2, 120, b"Jorge on the sofa"),
+            record(2, 25, b"sleeping"),
+        ]
+        .concat();
+        let resources = [
+            resource(0x03ED, b"", &[0; 16]),
+            resource(IPTC_RESOURCE, b"", &records),
+        ]
+        .concat();
+
+        assert_eq!(
+            from_embedded(&photoshop_jpeg(&resources)),
+            vec!["sleeping", "sofa"]
+        );
+    }
+
+    #[test]
+    fn merges_xmp_and_iptc_keywords() {
+        let resources = resource(IPTC_RESOURCE, b"", &record(2, 25, b"Sofa"));
+        let mut data = photoshop_jpeg(&resources);
+        data.extend_from_slice(
+            b"<x:xmpmeta><dc:subject><rdf:Bag><rdf:li>sofa</rdf:li><rdf:li>Cat &amp; box</rdf:li></rdf:Bag></dc:subject></x:xmpmeta>",
+        );
+
+        assert_eq!(from_embedded(&data), vec!["cat & box", "sofa"]);
+    }
+
+    #[test]
+    fn skips_odd_length_names_and_sizes() {
+        let resources = [
+            resource(0x0001, b"ab", b"odd!!"),
+            resource(0x0002, b"abc", b"x"),
+            resource(IPTC_RESOURCE, b"", b"found"),
+        ]
+        .concat();
+
+        assert_eq!(
+            photoshop_resource(&resources, IPTC_RESOURCE),
+            Some(&b"found"[..])
+        );
+        assert_eq!(photoshop_resource(&resources, 0x0002), Some(&b"x"[..]));
+        assert_eq!(photoshop_resource(&resources, 0x0003), None);
+    }
+
+    #[test]
+    fn stops_at_truncated_resources_and_records() {
+        let resources = resource(IPTC_RESOURCE, b"", b"found");
+        for end in 0..resources.len() - 1 {
+            assert_eq!(photoshop_resource(&resources[..end], IPTC_RESOURCE), None);
+        }
+
+        let records = [record(2, 25, b"sofa"), record(2, 25, b"sleeping")].concat();
+        assert_eq!(iptc_records(&records[..records.len() - 1]), vec!["sofa"]);
+        assert_eq!(iptc_records(&records[..3]), Vec::<String>::new());
+
+        // Extended lengths end the walk
+        let extended = [
+            vec![0x1C, 2, 25, 0x80, 0x04, 0, 0, 0, 4],
+            record(2, 25, b"sofa"),
+        ]
+        .concat();
+        assert_eq!(iptc_records(&extended), Vec::<String>::new());
+    }
+
+    #[test]
+    fn ignores_malformed_xmp_subjects() {
+        let data =
+            b"<x:xmpmeta><dc:subject><rdf:li</rdf:li><rdf:li>sofa</rdf:li></dc:subject></x:xmpmeta>";
+
+        assert_eq!(from_embedded(data), vec!["sofa"]);
+    }
+
+    #[test]
+    fn tags_from_nested_directories() {
+        let directory = PathBuf::from("/photos");
+
+        assert_eq!(
+            from_directory(&directory, Path::new("/photos/Cats/Vet Visit /jorge.jpg")),
+            vec!["cats", "vet visit"]
+        );
+        assert_eq!(
+            from_directory(&directory, Path::new("/photos/a/b/c/jorge.jpg")),
+            vec!["a", "b", "c"]
+        );
+        assert!(from_directory(&directory, Path::new("/photos/jorge.jpg")).is_empty());
+        assert!(from_directory(&directory, Path::new("/elsewhere/cats/jorge.jpg")).is_empty());
+    }
+}
@@ -7,23 +7,24 @@ use actix_web::{App, HttpServer, middleware, web};
 use confique::Config;
 use log::{error, info};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
-use crate::cache::{cache_cleanup, directory_watcher};
-#[actix_web::main]
-async fn main() -> anyhow::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-
-    let app_config = config::AppConfig::from_file(config::CONFIG_PATH)?;
-    let bind_address = app_config.address.clone();
+use crate::cache::{cache_cleanup, directory_watcher, prewarm_worker};
+use crate::config::AppConfig;
+use crate::endpoints::Collection;
+use crate::image_cache::cache::Cache;
+use crate::image_cache::derivatives::DerivativeCache;
+use crate::image_cache::encoder::EncoderPool;
 
-    let ssl_enabled = app_config.ssl;
-    let certificate_bundle = app_config.check();
+/// Loads the images of a config and starts the tasks that keep its cache up to date
+async fn start_cache(
+    config: &AppConfig,
+    memory: &Arc<DerivativeCache>,
+    encoder: &Arc<EncoderPool>,
+) -> Arc<Cache> {
+    let mut cache = Cache::new(config, Arc::clone(memory), Arc::clone(encoder));
+    cache.init(config).await;
 
-    let mut cache = image_cache::cache::Cache::from(app_config.cache_age);
-    cache.init(&app_config).await;
-
-    let shared_cache = Arc::new(Mutex::new(cache));
+    let shared_cache = Arc::new(cache);
 
     {
         let cache = Arc::clone(&shared_cache);
@@ -39,22 +40,79 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
+    {
+        let cache = Arc::clone(&shared_cache);
+        tokio::spawn(async move {
+            prewarm_worker(cache).await;
+        });
+    }
+
+    shared_cache
+}
+
+#[actix_web::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let app_config = config::AppConfig::from_file(config::CONFIG_PATH)?;
+
+    // `jorge_api audit` lists images that still contain GPS data instead of starting the server
+    if std::env::args().nth(1).as_deref() == Some("audit") {
+        let found = image_cache::privacy::audit(&app_config.all_directories());
+        std::process::exit(i32::from(found > 0));
+    }
+
+    let bind_address = app_config.address.clone();
+
+    let ssl_enabled = app_config.ssl;
+    let certificate_bundle = app_config.check();
+
+    // Collections share the memory budget and encoders instead of multiplying them
+    let memory = Arc::new(DerivativeCache::new(
+        app_config.memory_budget_mb * 1024 * 1024,
+    ));
+    let encoder = Arc::new(EncoderPool::new(
+        app_config.encode_concurrency,
+        app_config.encode_queue_depth,
+    ));
+
+    let shared_cache = start_cache(&app_config, &memory, &encoder).await;
+
+    let mut collections = Vec::new();
+    for name in app_config.collections.keys() {
+        let config = app_config.for_collection(name)?;
+        info!(
+            "Serving collection {} from {:?}",
+            name,
+            config.image_directories()
+        );
+        let cache = start_cache(&config, &memory, &encoder).await;
+        collections.push((name.clone(), config, cache));
+    }
+
     info!("Starting server at {}", bind_address);
 
     let app_config = app_config.clone();
     let shared_cache = Arc::clone(&shared_cache);
 
     let server = HttpServer::new(move || {
-        App::new()
+        let mut app = App::new()
             .wrap(middleware::Logger::default())
             .app_data(web::Data::new(app_config.clone()))
-            .app_data(web::Data::new(shared_cache.clone()))
-            .service(endpoints::api::routes::daily)
-            .service(endpoints::api::routes::get_image)
-            .service(endpoints::api::routes::list_images)
-            .service(endpoints::ui::routes::gallery)
-            .service(endpoints::ui::routes::about)
-            .service(endpoints::ui::routes::favicon)
+            .app_data(web::Data::from(shared_cache.clone()));
+
+        // Route names are shared, the top level has to be registered last to keep them
+        for (name, config, cache) in &collections {
+            app = app.service(
+                web::scope(&format!("/{}", name))
+                    .app_data(web::Data::new(config.clone()))
+                    .app_data(web::Data::from(cache.clone()))
+                    .app_data(web::Data::new(Collection { name: name.clone() }))
+                    .configure(endpoints::configure),
+            );
+        }
+
+        app.configure(endpoints::configure)
     });
 
     if ssl_enabled {